@@ -0,0 +1,70 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use tftp::packet::{Mode, Packet, FILE_NOT_FOUND, MAX_BLKSIZE, READ_OPCODE};
+
+const PAYLOAD_SIZES: [usize; 7] = [0, 64, 256, 512, 1432, 8192, MAX_BLKSIZE];
+
+fn packets() -> Vec<(String, Packet)> {
+    let mut packets = vec![
+        (
+            "request".to_owned(),
+            Packet::Request {
+                op_code: READ_OPCODE,
+                file: "pxelinux.0".to_owned(),
+                mode: Mode::Octet,
+                options: vec![("blksize".to_owned(), "1432".to_owned())],
+            },
+        ),
+        ("ack".to_owned(), Packet::new_ack(1)),
+        (
+            "error".to_owned(),
+            Packet::new_error(FILE_NOT_FOUND, "File not found"),
+        ),
+        (
+            "oack".to_owned(),
+            Packet::OAck {
+                options: vec![("tsize".to_owned(), "1048576".to_owned())],
+            },
+        ),
+    ];
+
+    for size in PAYLOAD_SIZES {
+        packets.push((
+            format!("data/{}", size),
+            Packet::new_data(1, vec![0xa5; size], size),
+        ));
+    }
+
+    packets
+}
+
+fn bench_serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize");
+
+    for (name, packet) in packets() {
+        group.throughput(Throughput::Bytes(packet.serialize().len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &packet, |b, packet| {
+            b.iter(|| black_box(packet).serialize())
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_deserialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("deserialize");
+
+    for (name, packet) in packets() {
+        let bytes = packet.serialize();
+
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &bytes, |b, bytes| {
+            b.iter(|| Packet::deserialize(black_box(bytes)).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_serialize, bench_deserialize);
+criterion_main!(benches);
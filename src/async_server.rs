@@ -0,0 +1,679 @@
+//! A server running its transfers as tokio tasks rather than OS threads, for
+//! when thousands of clients turn up at once, as in a PXE boot storm.
+//!
+//! It shares its `Config` and request checks with the threaded server in
+//! [`crate::server`], but doesn't track active transfers or report their
+//! progress or metrics, follow clients that change port, keep reads off files
+//! being written, serve the `.index` listing and gzipped copies of files,
+//! answer `.health`, send checksums, cap how long a transfer may take, serve
+//! files from memory maps, hold back its sends by `artificial_delay`, buffer
+//! its file I/O by `io_buffer_size`, reuse block buffers between transfers, log
+//! uploads to the log sink, set uploads' mtime and mode, keep to the RFCs under
+//! `strict`, log accepted requests to `request_log`, stream to and from FIFOs,
+//! or send an ERROR when a transfer panics. A `Config` that turns on any of
+//! those is refused, rather than served without it.
+
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::mpsc::{self, error::SendError, UnboundedReceiver, UnboundedSender};
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+
+use crate::netascii::NetAsciiReader;
+use crate::packet::{
+    Mode, Packet, ACCESS_VIOLATION, DEFAULT_BLKSIZE, DISK_FULL, ILLEGAL_OP, OPTION_NEGOTIATION,
+    READ_OPCODE, SEE_MSG, UNKNOWN_TID, WRITE_OPCODE,
+};
+use crate::server::{
+    abort_line, authorize, bind_to_device, blksize, check_peer, chroot, file_size, fill_block,
+    fit_blksize, negotiate_blksize, negotiate_serverinfo, oversized_request, parse_range,
+    preflight_write, probe, request_buf_len, resolve, root_for, serverinfo, timeout, transfer_mode,
+    tsize, Config, NotFoundBehavior, ShutdownHandle, ACK_TIMEOUT, MAX_RETRANSMITS,
+    SHUTDOWN_POLL_INTERVAL,
+};
+
+/// Fails with the name of the first setting in `config` that this server
+/// would ignore, if there is one
+fn check_supported(config: &Config) -> io::Result<()> {
+    let defaults = Config::default();
+    let unsupported = [
+        ("tolerate_nat_rebind", config.tolerate_nat_rebind),
+        ("enable_index", config.enable_index),
+        ("enable_health", config.enable_health),
+        ("enable_log_sink", config.enable_log_sink),
+        ("log_sink_writer", config.log_sink_writer.is_some()),
+        ("preserve_metadata", config.preserve_metadata),
+        (
+            "io_buffer_size",
+            config.io_buffer_size != defaults.io_buffer_size,
+        ),
+        (
+            "buffer_pool_size",
+            config.buffer_pool_size != defaults.buffer_pool_size,
+        ),
+        (
+            "busy_behavior",
+            config.busy_behavior != defaults.busy_behavior,
+        ),
+        (
+            "max_transfer_duration",
+            config.max_transfer_duration.is_some(),
+        ),
+        ("strict", config.strict),
+        ("artificial_delay", !config.artificial_delay.is_zero()),
+        ("progress_tx", config.progress_tx.is_some()),
+        ("metrics", config.metrics.is_some()),
+        ("request_log", config.request_log.is_some()),
+        #[cfg(feature = "gzip")]
+        ("gzip", config.gzip),
+        #[cfg(feature = "mmap")]
+        ("mmap", config.mmap),
+    ];
+
+    match unsupported.into_iter().find(|&(_, set)| set) {
+        Some((name, _)) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} isn't supported by the async server", name),
+        )),
+        None => Ok(()),
+    }
+}
+
+pub struct Server {
+    socket: Arc<UdpSocket>,
+    config: Config,
+    shutdown: Arc<AtomicBool>,
+}
+
+struct Connection {
+    tx: UnboundedSender<Packet>,
+    handle: JoinHandle<()>,
+}
+
+impl Server {
+    pub async fn bind<A: ToSocketAddrs>(addr: A, config: Config) -> io::Result<Self> {
+        check_supported(&config)?;
+
+        Self::from_socket(UdpSocket::bind(addr).await?, config)
+    }
+
+    /// Serves on a socket that was bound elsewhere
+    pub fn from_socket(socket: UdpSocket, config: Config) -> io::Result<Self> {
+        check_supported(&config)?;
+
+        Ok(Self {
+            socket: Arc::new(socket),
+            config,
+            shutdown: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
+    }
+
+    pub async fn run(&self) -> io::Result<()> {
+        if let Some(interface) = &self.config.interface {
+            bind_to_device(&*self.socket, interface)?;
+        }
+
+        let root = if self.config.chroot {
+            chroot(&self.config.root)?;
+            PathBuf::from("/")
+        } else {
+            self.config.root.clone()
+        };
+
+        let socket = &self.socket;
+        let mut connections: HashMap<SocketAddr, Connection> = HashMap::new();
+
+        // Grown only once a transfer negotiates a larger block size
+        let mut buf = vec![0; request_buf_len(&self.config)];
+        loop {
+            connections.retain(|_, conn| !conn.handle.is_finished());
+
+            let shutting_down = self.shutdown.load(Ordering::Relaxed);
+            if shutting_down && connections.is_empty() {
+                return Ok(());
+            }
+
+            let (len, addr) =
+                match time::timeout(SHUTDOWN_POLL_INTERVAL, socket.recv_from(&mut buf)).await {
+                    Ok(res) => res?,
+                    Err(_) => continue,
+                };
+
+            if oversized_request(&self.config, &buf[..len]) {
+                let err = Packet::new_error(ILLEGAL_OP, "Request too long");
+                send(socket, err, addr).await?;
+                continue;
+            }
+
+            let packet = match Packet::deserialize(&buf[..len]) {
+                Ok(p) => p,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    send(socket, Packet::new_error(ILLEGAL_OP, e.to_string()), addr).await?;
+                    continue;
+                }
+            };
+
+            match packet {
+                Packet::Request {
+                    op_code,
+                    file,
+                    mode,
+                    mut options,
+                } => {
+                    if shutting_down {
+                        let err = Packet::new_error(SEE_MSG, "Server is shutting down");
+                        send(socket, err, addr).await?;
+                        continue;
+                    }
+                    if self
+                        .config
+                        .max_connections
+                        .is_some_and(|max| connections.len() >= max)
+                    {
+                        let err = Packet::new_error(SEE_MSG, "Too many transfers");
+                        send(socket, err, addr).await?;
+                        continue;
+                    }
+                    // Picked by the mode asked for, even if it's served in
+                    // another
+                    let root = root_for(&self.config, &root, addr.ip(), &mode);
+                    let mode = transfer_mode(&self.config, mode);
+
+                    if let Err(err) = authorize(&self.config, op_code, &file) {
+                        send(socket, err, addr).await?;
+                        continue;
+                    }
+                    if let Err(err) = check_peer(&self.config, addr.ip(), op_code) {
+                        send(socket, err, addr).await?;
+                        continue;
+                    }
+
+                    let path = match resolve(&root, &file) {
+                        Some(path) => path,
+                        None => {
+                            let err = Packet::new_error(ACCESS_VIOLATION, "Access violation");
+                            send(socket, err, addr).await?;
+                            continue;
+                        }
+                    };
+
+                    if op_code == READ_OPCODE
+                        && self.config.not_found_behavior == NotFoundBehavior::Drop
+                        && !path.exists()
+                    {
+                        eprintln!("Dropping request from {} for missing {}", addr, file);
+                        continue;
+                    }
+
+                    if op_code == READ_OPCODE {
+                        if let Some(oack) = probe(&options, file_size(&path)) {
+                            send(socket, oack, addr).await?;
+                            continue;
+                        }
+                    }
+
+                    negotiate_blksize(&self.config, &file, &mut options);
+                    negotiate_serverinfo(&self.config, &mut options);
+                    fit_blksize(&mut buf, &options);
+
+                    if op_code == WRITE_OPCODE {
+                        if let Err(err) = preflight_write(&self.config, &root, &path, &options) {
+                            send(socket, err, addr).await?;
+                            continue;
+                        }
+                    }
+
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    let socket = socket.clone();
+                    let max_blocks = self.config.max_blocks;
+
+                    let handle = if op_code == READ_OPCODE {
+                        task::spawn(async move {
+                            if let Err(e) = read_task(socket, addr, rx, path, mode, options).await {
+                                eprintln!("Error: {}", e);
+                            }
+                        })
+                    } else {
+                        task::spawn(async move {
+                            if let Err(e) =
+                                write_task(socket, addr, rx, path, options, max_blocks).await
+                            {
+                                eprintln!("Error: {}", e);
+                            }
+                        })
+                    };
+
+                    connections.insert(addr, Connection { tx, handle });
+                }
+
+                Packet::OAck { .. } => {
+                    let err = Packet::new_error(ILLEGAL_OP, "Unexpected OACK");
+                    socket.send_to(&err.serialize(), addr).await?;
+
+                    if let Some(conn) = connections.remove(&addr) {
+                        let _ = conn.tx.send(err);
+                    }
+                }
+
+                // Sent to tasks: Data, Ack, Error
+                packet => match connections.get(&addr) {
+                    Some(conn) => {
+                        // The task may have finished since the table was
+                        // last swept, so the packet is stale
+                        if let Err(SendError(packet)) = conn.tx.send(packet) {
+                            connections.remove(&addr);
+                            if !packet.is_error() {
+                                let err = Packet::new_error(UNKNOWN_TID, "Transfer has ended");
+                                send(socket, err, addr).await?;
+                            }
+                        }
+                    }
+                    // ERRORs are never answered (RFC 1350)
+                    None if packet.is_error() => {}
+                    None => send(socket, Packet::new_error(UNKNOWN_TID, ""), addr).await?,
+                },
+            }
+        }
+    }
+}
+
+async fn send(socket: &UdpSocket, packet: Packet, dst: SocketAddr) -> io::Result<()> {
+    socket.send_to(&packet.serialize(), dst).await?;
+    Ok(())
+}
+
+/// The async counterpart of `server::read_process`
+async fn read_task(
+    socket: Arc<UdpSocket>,
+    dst: SocketAddr,
+    mut rx: UnboundedReceiver<Packet>,
+    file: PathBuf,
+    mode: Mode,
+    options: Vec<(String, String)>,
+) -> io::Result<()> {
+    if mode == Mode::Mail {
+        let err = Packet::new_error(ILLEGAL_OP, "Mail mode can't be used to read");
+        return send(&socket, err, dst).await;
+    }
+
+    let mut file = match fs::File::open(file).await {
+        Ok(f) => f.into_std().await,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return send(&socket, Packet::from_io_error(&e), dst).await;
+        }
+    };
+
+    // Options we agreed to, echoed back to the client in an OACK
+    let mut accepted = Vec::new();
+    // Served as it was when opened, like the threaded server does
+    let mut size = file.metadata()?.len();
+    let mut len = size;
+
+    let blksize = match blksize(&options) {
+        Some(blksize) => {
+            accepted.push(("blksize".to_owned(), blksize.to_string()));
+            blksize
+        }
+        None => DEFAULT_BLKSIZE,
+    };
+
+    if let Some((_, range)) = options.iter().find(|(name, _)| name == "range") {
+        match parse_range(range, size) {
+            Some((start, end)) => {
+                file.seek(SeekFrom::Start(start))?;
+                len = end - start;
+                size = len;
+                accepted.push(("range".to_owned(), format!("{}-{}", start, end)));
+            }
+            None => {
+                let err = Packet::new_error(OPTION_NEGOTIATION, "Invalid range");
+                return send(&socket, err, dst).await;
+            }
+        }
+    }
+
+    if tsize(&options).is_some() {
+        accepted.push(("tsize".to_owned(), size.to_string()));
+    }
+    let timeout = match timeout(&options) {
+        Some((option, timeout)) => {
+            accepted.push(option);
+            timeout
+        }
+        None => ACK_TIMEOUT,
+    };
+    accepted.extend(serverinfo(&options));
+
+    if !accepted.is_empty() {
+        let oack = Packet::OAck { options: accepted }.serialize();
+        if !await_ack(&socket, dst, &mut rx, &oack, 0, timeout).await? {
+            return Ok(());
+        }
+    }
+
+    let reader: Box<dyn Read + Send> = match mode {
+        Mode::NetAscii => Box::new(NetAsciiReader::new(BufReader::new(file.take(len)))),
+        _ => Box::new(file.take(len)),
+    };
+    send_file(&socket, dst, &mut rx, reader, blksize, timeout).await
+}
+
+/// Reads the next block on the blocking pool, handing the reader back
+async fn read_block(
+    mut reader: Box<dyn Read + Send>,
+    blksize: usize,
+) -> io::Result<(Box<dyn Read + Send>, Vec<u8>, usize)> {
+    task::spawn_blocking(move || {
+        let mut data = vec![0; blksize];
+        let len = fill_block(&mut reader, &mut data)?;
+        Ok((reader, data, len))
+    })
+    .await?
+}
+
+async fn send_file(
+    socket: &UdpSocket,
+    dst: SocketAddr,
+    rx: &mut UnboundedReceiver<Packet>,
+    mut reader: Box<dyn Read + Send>,
+    blksize: usize,
+    timeout: Duration,
+) -> io::Result<()> {
+    let mut current_block: u16 = 1;
+    let mut res = Vec::with_capacity(blksize + 4);
+
+    loop {
+        let (next, data, len) = read_block(reader, blksize).await?;
+        reader = next;
+
+        Packet::new_data(current_block, data, len).serialize_into(&mut res);
+        if !await_ack(socket, dst, rx, &res, current_block, timeout).await? {
+            return Ok(());
+        }
+
+        // Block numbers roll over for files bigger than 32 MiB
+        current_block = current_block.wrapping_add(1);
+
+        if len < blksize {
+            return Ok(());
+        }
+    }
+}
+
+/// Sends `packet` and waits for the client to ACK `block`, resending it
+/// each time `timeout` passes without one. Returns false if the client sent an ERROR or went quiet.
+async fn await_ack(
+    socket: &UdpSocket,
+    dst: SocketAddr,
+    rx: &mut UnboundedReceiver<Packet>,
+    packet: &[u8],
+    block: u16,
+    timeout: Duration,
+) -> io::Result<bool> {
+    socket.send_to(packet, dst).await?;
+
+    let mut retransmits = 0;
+    loop {
+        match time::timeout(timeout, rx.recv()).await {
+            Ok(Some(Packet::Ack { block: acked })) if acked == block => return Ok(true),
+            Ok(Some(Packet::Error { code, msg })) => {
+                eprintln!("{}", abort_line(dst, code, &msg));
+                return Ok(false);
+            }
+            Ok(Some(_)) => continue,
+            Ok(None) => return Ok(false),
+            Err(_) if retransmits < MAX_RETRANSMITS => {
+                retransmits += 1;
+                socket.send_to(packet, dst).await?;
+            }
+            Err(_) => {
+                eprintln!("Gave up waiting for ACK {} from {}", block, dst);
+                return Ok(false);
+            }
+        }
+    }
+}
+
+/// The async counterpart of `server::write_process`
+async fn write_task(
+    socket: Arc<UdpSocket>,
+    dst: SocketAddr,
+    mut rx: UnboundedReceiver<Packet>,
+    file: PathBuf,
+    options: Vec<(String, String)>,
+    max_blocks: Option<u64>,
+) -> io::Result<()> {
+    let mut file = match fs::File::create(file).await {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return send(&socket, Packet::from_io_error(&e), dst).await;
+        }
+    };
+
+    // Options we agreed to, echoed back to the client in an OACK
+    let mut accepted = Vec::new();
+
+    let blksize = match blksize(&options) {
+        Some(blksize) => {
+            accepted.push(("blksize".to_owned(), blksize.to_string()));
+            blksize
+        }
+        None => DEFAULT_BLKSIZE,
+    };
+    let declared = tsize(&options);
+    if let Some(tsize) = declared {
+        accepted.push(("tsize".to_owned(), tsize.to_string()));
+    }
+    accepted.extend(serverinfo(&options));
+
+    let res = if accepted.is_empty() {
+        Packet::new_ack(0)
+    } else {
+        Packet::OAck { options: accepted }
+    };
+    send(&socket, res, dst).await?;
+
+    let mut current_block: u16 = 1;
+    let mut blocks_written: u64 = 0;
+    let mut received: u64 = 0;
+    while let Some(packet) = rx.recv().await {
+        let last = packet.is_final_data(blksize);
+
+        match packet {
+            Packet::Data { block, data, .. } => {
+                // A repeat of the block we last ACKed means that ACK was lost
+                if block == current_block.wrapping_sub(1) {
+                    send(&socket, Packet::new_ack(block), dst).await?;
+                    continue;
+                }
+                if block != current_block {
+                    continue;
+                }
+
+                if max_blocks.is_some_and(|max| blocks_written >= max) {
+                    eprintln!("{} sent more than {:?} blocks", dst, max_blocks);
+                    send(
+                        &socket,
+                        Packet::new_error(DISK_FULL, "Too many blocks"),
+                        dst,
+                    )
+                    .await?;
+                    break;
+                }
+                blocks_written += 1;
+
+                received += data.len() as u64;
+                if let Some(tsize) = declared.filter(|&tsize| received > tsize) {
+                    eprintln!("{} sent more than its declared tsize of {}", dst, tsize);
+                    send(
+                        &socket,
+                        Packet::new_error(ILLEGAL_OP, "More data than declared tsize"),
+                        dst,
+                    )
+                    .await?;
+                    break;
+                }
+
+                file.write_all(&data).await?;
+                file.flush().await?;
+
+                send(&socket, Packet::new_ack(block), dst).await?;
+                current_block = current_block.wrapping_add(1);
+
+                if let Some(tsize) = declared.filter(|&tsize| last && received < tsize) {
+                    eprintln!(
+                        "{} sent {} bytes, short of its declared tsize of {}",
+                        dst, received, tsize
+                    );
+                }
+
+                if last {
+                    return dally(&socket, dst, &mut rx, block).await;
+                }
+            }
+            Packet::Error { code, msg } => {
+                eprintln!("{}", abort_line(dst, code, &msg));
+                break;
+            }
+            _ => continue,
+        }
+    }
+
+    Ok(())
+}
+
+/// Lingers for one timeout after the final ACK of a write, ACKing the final
+/// block again if the client resends it because that ACK was lost
+async fn dally(
+    socket: &UdpSocket,
+    dst: SocketAddr,
+    rx: &mut UnboundedReceiver<Packet>,
+    final_block: u16,
+) -> io::Result<()> {
+    while let Ok(Some(packet)) = time::timeout(ACK_TIMEOUT, rx.recv()).await {
+        match packet {
+            Packet::Data { block, .. } if block == final_block => {
+                send(socket, Packet::new_ack(block), dst).await?;
+            }
+            Packet::Error { .. } => break,
+            _ => continue,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+    use tokio::time;
+
+    use super::Server;
+    use crate::packet::{Packet, MAX_BLKSIZE, READ_OPCODE};
+    use crate::server::Config;
+
+    async fn recv_from(client: &UdpSocket) -> Packet {
+        let mut buf = vec![0; MAX_BLKSIZE + 4];
+        let (len, _) = time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        Packet::deserialize(&buf[..len]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_async_download() {
+        let root = std::env::temp_dir().join(format!("tftp-{}-async", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let contents: Vec<u8> = (0..700).map(|i| i as u8).collect();
+        fs::write(root.join("name.bin"), &contents).unwrap();
+
+        let config = Config {
+            root: root.clone(),
+            ..Config::default()
+        };
+        let server = Arc::new(Server::bind("127.0.0.1:0", config).await.unwrap());
+        let addr = server.local_addr().unwrap();
+
+        let running = server.clone();
+        let handle = tokio::spawn(async move { running.run().await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut rrq = READ_OPCODE.to_be_bytes().to_vec();
+        rrq.extend_from_slice(b"name.bin\0octet\0");
+        client.send_to(&rrq, addr).await.unwrap();
+
+        let mut received = Vec::new();
+        loop {
+            let (block, data, len) = match recv_from(&client).await {
+                Packet::Data { block, data, len } => (block, data, len),
+                _ => panic!("did not get expected packet: Data"),
+            };
+            received.extend_from_slice(&data[..len]);
+
+            client
+                .send_to(&Packet::new_ack(block).serialize(), addr)
+                .await
+                .unwrap();
+
+            if len < 512 {
+                break;
+            }
+        }
+        assert_eq!(received, contents);
+
+        server.shutdown_handle().shutdown();
+        handle.await.unwrap().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_config() {
+        let config = Config {
+            enable_index: true,
+            ..Config::default()
+        };
+        let err = Server::bind("127.0.0.1:0", config)
+            .await
+            .err()
+            .expect("a config enabling the index was accepted");
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(
+            err.to_string(),
+            "enable_index isn't supported by the async server"
+        );
+
+        let config = Config {
+            artificial_delay: Duration::from_millis(10),
+            ..Config::default()
+        };
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert!(Server::from_socket(socket, config).is_err());
+    }
+}
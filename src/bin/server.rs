@@ -1,233 +1,141 @@
-use std::collections::HashMap;
-use std::fs;
-use std::io::{self, BufWriter, Cursor, Read, Write};
-use std::net::{SocketAddr, UdpSocket};
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::sync::Arc;
-use std::thread;
-
-use tftp::packet::{
-    Packet, FILE_NOT_FOUND, ILLEGAL_OP, READ_OPCODE, SEE_MSG, UNKNOWN_TID, WRITE_OPCODE,
-};
-
-fn main() -> io::Result<()> {
-    let socket = UdpSocket::bind("0.0.0.0:69")?;
-    let socket = Arc::new(socket);
-    let mut connections: HashMap<SocketAddr, Sender<Packet>> = HashMap::new();
-
-    let mut buf = [0; 1024];
-    loop {
-        let (_, addr) = socket.recv_from(&mut buf)?;
-
-        let packet = match Packet::deserialize(&buf) {
-            Ok(p) => p,
-            Err(e) => {
-                eprintln!("Error: {}", e);
-                socket.send_to(
-                    Packet::new_error(ILLEGAL_OP, "").serialize().as_slice(),
-                    addr,
-                )?;
-
-                continue;
-            }
-        };
-
-        buf = [0; 1024];
-
-        match packet {
-            // Create processes for these:
-            Packet::Request {
-                op_code,
-                file,
-                mode: _,
-            } => {
-                let (tx, rx) = mpsc::channel();
-                connections.insert(addr, tx);
-
-                let socket = socket.clone();
-
-                if op_code == READ_OPCODE {
-                    thread::spawn(move || {
-                        if let Err(e) = read_process(socket, addr, rx, file) {
-                            eprintln!("Error: {}", e);
-                        }
-                    });
-                } else if op_code == WRITE_OPCODE {
-                    thread::spawn(move || {
-                        if let Err(e) = write_process(socket, addr, rx, file) {
-                            eprintln!("Error: {}", e)
-                        }
-                    });
-                } else {
-                    panic!("Request op_code is neither 1 or 2");
-                }
-            }
-
-            // Sent to processes: Data, Ack, Error
-            packet => {
-                if let Some(tx) = connections.get(&addr) {
-                    if let Err(e) = tx.send(packet) {
-                        eprintln!("{}", e);
-                        // TODO: remove from connections map
-                    }
-                } else {
-                    socket.send_to(
-                        Packet::new_error(UNKNOWN_TID, "").serialize().as_slice(),
-                        addr,
-                    )?;
-                }
-            }
-        }
-    }
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use tftp::server::{Config, Server};
+
+const USAGE: &str = "\
+Usage: server [OPTIONS]
+
+Options:
+      --addr <ADDR>              Address to listen on [default: 0.0.0.0:69]
+      --root <DIR>               Directory to serve files from [default: .]
+      --read-only                Refuse all writes
+      --blksize <BYTES>          Block size offered to negotiating clients
+      --max-connections <N>      Most transfers to run at once
+      --log-requests             Log each accepted request to stderr
+  -h, --help                     Print this help
+";
+
+/// What the command line asks for
+struct Args {
+    addr: String,
+    config: Config,
 }
 
-/// Initial Connection Protocol for reading a file
-/// 1. Host  A  sends  a  "RRQ"  to  host  B  with  source= A's TID,
-///    destination= 69.
-/// 2. Host B sends a "DATA" (with block number= 1) to host  A  with
-///    source= B's TID, destination= A's TID.
-///
-/// RRQ and ACK packets are awknowledged by DATA and ERROR packets
-fn read_process(
-    socket: Arc<UdpSocket>,
-    dst: SocketAddr,
-    rx: Receiver<Packet>,
-    file: String,
-) -> io::Result<()> {
-    let file = match fs::File::open(file) {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            socket.send_to(
-                Packet::new_error(FILE_NOT_FOUND, "File not found")
-                    .serialize()
-                    .as_slice(),
-                dst,
-            )?;
-
-            return Ok(());
-        }
+/// Parses the arguments after the program name. `Ok(None)` means help was
+/// asked for.
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Option<Args>, String> {
+    let mut parsed = Args {
+        addr: "0.0.0.0:69".to_owned(),
+        config: Config::default(),
     };
 
-    let mut cursor = Cursor::new(file);
-    let mut start = cursor.position();
-    let end = cursor.get_ref().metadata().unwrap().len();
-
-    let mut current_block = 1;
-
-    'transfer: while start < end {
-        let mut data = vec![0; 512];
-        // Read file into buffer
-        let len = cursor.get_ref().read(&mut data)?;
-
-        // Send data
-        let res = Packet::new_data(current_block, data, len).serialize();
-
-        socket.send_to(&res, dst)?;
-
-        // Wait for ACK (timeout?)
-        'recv: while let Ok(e) = rx.recv() {
-            match e {
-                Packet::Data {
-                    block: _,
-                    data: _,
-                    len: _,
-                } => {
-                    // Since this is a read request we're not expecting data packets
-                    // from the client
-                    continue;
-                }
-                Packet::Ack { block } => {
-                    // Need to make sure this block matches what we sent
-                    // Else keep waiting
-                    if block == current_block {
-                        current_block += 1;
-                        break 'recv;
-                    }
-                }
-                Packet::Error { code, msg } => {
-                    eprintln!("Error {}: {}", code, msg);
-                    break 'transfer;
-                }
-                _ => unreachable!(),
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--addr" => parsed.addr = value()?,
+            "--root" => parsed.config.root = PathBuf::from(value()?),
+            "--read-only" => parsed.config.read_only = true,
+            "--blksize" => parsed.config.blksize = number(&arg, &value()?)?,
+            "--max-connections" => parsed.config.max_connections = Some(number(&arg, &value()?)?),
+            "--log-requests" => {
+                parsed.config.request_log = Some(Arc::new(Mutex::new(io::stderr())))
             }
+            _ => return Err(format!("unknown argument {}", arg)),
         }
-
-        start += len as u64;
-        cursor.set_position(len as u64);
     }
 
-    Ok(())
+    Ok(Some(parsed))
 }
 
-/// Initial Connection Protocol for writing a file
-/// 1. Host A sends  a  "WRQ"  to  host  B  with  source=  A's  TID,
-///    destination= 69.
-/// 2. Host  B  sends  a "ACK" (with block number= 0) to host A with
-///    source= B's TID, destination= A's TID.
-///
-/// WRQ and DATA packets are awknowledged by ACK and ERROR packets
-fn write_process(
-    socket: Arc<UdpSocket>,
-    dst: SocketAddr,
-    rx: Receiver<Packet>,
-    file: String,
-) -> io::Result<()> {
-    let file = match fs::File::create(file) {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            socket.send_to(
-                Packet::new_error(SEE_MSG, "There was an error creating/accessing the file")
-                    .serialize()
-                    .as_slice(),
-                dst,
-            )?;
+fn number(arg: &str, value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} needs a number, not {}", arg, value))
+}
 
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", USAGE);
             return Ok(());
         }
+        Err(e) => {
+            eprint!("error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
     };
 
-    let mut writer = BufWriter::new(file);
+    let server = Server::builder()
+        .bind(args.addr)
+        .config(args.config)
+        .build()?;
 
-    // Send ack
-    let mut current_block = 0;
-    let res = Packet::new_ack(current_block).serialize();
+    #[cfg(all(unix, feature = "signals"))]
+    server.handle_signals()?;
 
-    socket.send_to(&res, dst)?;
-    current_block += 1;
+    server.run()?;
 
-    'recv: while let Ok(e) = rx.recv() {
-        match e {
-            Packet::Data { block, data, len } => {
-                // Write to file
-                if block != current_block {
-                    continue;
-                }
+    Ok(())
+}
 
-                writer.write_all(&data)?;
-                writer.flush()?;
+#[cfg(test)]
+mod test {
+    use std::path::Path;
 
-                socket.send_to(Packet::new_ack(current_block).serialize().as_slice(), dst)?;
+    use super::parse_args;
 
-                current_block += 1;
+    fn parse(args: &[&str]) -> Result<Option<super::Args>, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
 
-                if len < 512 {
-                    break 'recv;
-                }
-            }
-            Packet::Ack { block: _ } => {
-                // Since this is a write request we're not expecting ack packets
-                // from the client
-                continue;
-            }
-            Packet::Error { code, msg } => {
-                eprintln!("Error {}: {}", code, msg);
-                break 'recv;
-            }
-            _ => unreachable!(),
-        }
+    #[test]
+    fn test_parse_args() {
+        let args = parse(&[
+            "--addr",
+            "127.0.0.1:6969",
+            "--root",
+            "/srv/tftp",
+            "--read-only",
+            "--blksize",
+            "1432",
+            "--max-connections",
+            "64",
+            "--log-requests",
+        ])
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(args.addr, "127.0.0.1:6969");
+        assert_eq!(args.config.root, Path::new("/srv/tftp"));
+        assert!(args.config.read_only);
+        assert_eq!(args.config.blksize, 1432);
+        assert_eq!(args.config.max_connections, Some(64));
+        assert!(args.config.request_log.is_some());
     }
 
-    Ok(())
+    #[test]
+    fn test_parse_args_defaults() {
+        let args = parse(&[]).unwrap().unwrap();
+
+        assert_eq!(args.addr, "0.0.0.0:69");
+        assert!(!args.config.read_only);
+        assert_eq!(args.config.max_connections, None);
+        assert!(args.config.request_log.is_none());
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(parse(&["--help"]).unwrap().is_none());
+        assert!(parse(&["--blksize"]).is_err());
+        assert!(parse(&["--blksize", "big"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+    }
 }
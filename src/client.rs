@@ -0,0 +1,435 @@
+//! A blocking client, fetching a file from a server or sending one to it in
+//! octet mode with the default block size.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::packet::{
+    self, Mode, Packet, ACCESS_VIOLATION, DEFAULT_BLKSIZE, DISK_FULL, FILE_EXISTS, FILE_NOT_FOUND,
+    MAX_BLKSIZE, READ_OPCODE, UNKNOWN_TID, WRITE_OPCODE,
+};
+use crate::server::{fill_block, ACK_TIMEOUT, MAX_RETRANSMITS};
+
+/// How a client transfer is run
+pub struct ClientConfig {
+    /// Local address to send from, which also picks the interface. Left
+    /// unset, it's any port on the unspecified address of the server's
+    /// family, so IPv4 and IPv6 servers alike can be reached.
+    pub bind: Option<SocketAddr>,
+    /// How long to wait on the server before sending the last packet again
+    pub timeout: Duration,
+    /// Times the last packet is sent again before giving up
+    pub retries: u32,
+    /// Set from elsewhere to cancel the transfer. It's checked between
+    /// blocks, and the server is sent an ERROR so it stops too.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            bind: None,
+            timeout: ACK_TIMEOUT,
+            retries: MAX_RETRANSMITS,
+            cancel: None,
+        }
+    }
+}
+
+/// What a transfer cancelled through [`ClientConfig::cancel`] fails with,
+/// wrapped in an [`io::Error`]
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transfer cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Downloads `file` from `server` into `out`, returning how many bytes it
+/// was
+pub fn get<W: Write>(server: SocketAddr, file: &str, out: W) -> io::Result<u64> {
+    get_with(&ClientConfig::default(), server, file, out)
+}
+
+/// Like [`get`], run as `config` says
+pub fn get_with<W: Write>(
+    config: &ClientConfig,
+    server: SocketAddr,
+    file: &str,
+    mut out: W,
+) -> io::Result<u64> {
+    let mut conn = Conn::open(config, server)?;
+    conn.send(request(READ_OPCODE, file)?)?;
+
+    let mut block: u16 = 1;
+    let mut size = 0;
+    loop {
+        conn.check_cancel()?;
+        match conn.recv()? {
+            Packet::Data {
+                block: received,
+                data,
+                ..
+            } if received == block => {
+                out.write_all(&data)?;
+                size += data.len() as u64;
+                conn.send(Packet::new_ack(block))?;
+
+                if data.len() < DEFAULT_BLKSIZE {
+                    return Ok(size);
+                }
+                block = block.wrapping_add(1);
+            }
+            // Our ACK of it was lost, so the server sent the block again
+            Packet::Data {
+                block: received, ..
+            } if received == block.wrapping_sub(1) => conn.resend()?,
+            Packet::Error { code, msg } => return Err(server_error(code, &msg)),
+            _ => continue,
+        }
+    }
+}
+
+/// Uploads everything in `input` to `server` as `file`, returning how many
+/// bytes it was
+pub fn put<R: Read>(server: SocketAddr, file: &str, input: R) -> io::Result<u64> {
+    put_with(&ClientConfig::default(), server, file, input)
+}
+
+/// Like [`put`], run as `config` says
+pub fn put_with<R: Read>(
+    config: &ClientConfig,
+    server: SocketAddr,
+    file: &str,
+    mut input: R,
+) -> io::Result<u64> {
+    let mut conn = Conn::open(config, server)?;
+    conn.send(request(WRITE_OPCODE, file)?)?;
+
+    // The WRQ is acknowledged as block 0
+    let mut block: u16 = 0;
+    let mut size = 0;
+    let mut sent_last = false;
+    loop {
+        conn.check_cancel()?;
+        match conn.recv()? {
+            Packet::Ack { block: acked } if acked == block => {
+                if sent_last {
+                    return Ok(size);
+                }
+
+                let mut data = vec![0; DEFAULT_BLKSIZE];
+                let len = fill_block(&mut input, &mut data)?;
+                size += len as u64;
+                sent_last = len < DEFAULT_BLKSIZE;
+
+                block = block.wrapping_add(1);
+                conn.send(Packet::new_data(block, data, len))?;
+            }
+            Packet::Error { code, msg } => return Err(server_error(code, &msg)),
+            // An earlier ACK arriving late is ignored, or every block would
+            // go out twice from then on
+            _ => continue,
+        }
+    }
+}
+
+fn request(op_code: u16, file: &str) -> io::Result<Packet> {
+    Packet::new_request(op_code, file, Mode::Octet, Vec::new())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// The server's ERROR as an I/O error, of the kind closest to its code
+fn server_error(code: u16, msg: &str) -> io::Error {
+    let kind = match code {
+        FILE_NOT_FOUND => io::ErrorKind::NotFound,
+        ACCESS_VIOLATION => io::ErrorKind::PermissionDenied,
+        DISK_FULL => io::ErrorKind::StorageFull,
+        FILE_EXISTS => io::ErrorKind::AlreadyExists,
+        _ => io::ErrorKind::Other,
+    };
+
+    io::Error::new(kind, format!("server error {}: {}", code, msg))
+}
+
+/// The socket of one transfer, which keeps to the port the server first
+/// answers from (its transfer ID) and resends the last packet on a timeout
+struct Conn<'a> {
+    socket: UdpSocket,
+    config: &'a ClientConfig,
+    peer: SocketAddr,
+    /// Whether `peer` is the server's transfer ID yet, rather than the
+    /// address the request went to
+    locked: bool,
+    last: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl<'a> Conn<'a> {
+    fn open(config: &'a ClientConfig, server: SocketAddr) -> io::Result<Self> {
+        let bind = config.bind.unwrap_or_else(|| match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        });
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_read_timeout(Some(config.timeout))?;
+
+        Ok(Self {
+            socket,
+            config,
+            peer: server,
+            locked: false,
+            last: Vec::new(),
+            buf: vec![0; MAX_BLKSIZE + 4],
+        })
+    }
+
+    /// Fails with [`Cancelled`] once the transfer's been cancelled, telling
+    /// the server first
+    fn check_cancel(&self) -> io::Result<()> {
+        let cancelled = self
+            .config
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed));
+        if !cancelled {
+            return Ok(());
+        }
+
+        let err = Packet::new_error(0, "Cancelled");
+        let _ = self.socket.send_to(&err.serialize(), self.peer);
+
+        Err(io::Error::other(Cancelled))
+    }
+
+    fn send(&mut self, packet: Packet) -> io::Result<()> {
+        self.last.clear();
+        packet.serialize_into(&mut self.last);
+        self.resend()
+    }
+
+    fn resend(&self) -> io::Result<()> {
+        self.socket.send_to(&self.last, self.peer)?;
+        Ok(())
+    }
+
+    /// The next packet from the server, resending the last one each time
+    /// `timeout` passes without one
+    fn recv(&mut self) -> io::Result<Packet> {
+        let mut retries = 0;
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut self.buf) {
+                Ok(res) => res,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if retries == self.config.retries {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "server stopped responding",
+                        ));
+                    }
+                    retries += 1;
+                    self.resend()?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            if self.locked && from != self.peer {
+                // Someone other than our server; tell them, and carry on
+                // (RFC 1350)
+                let err = Packet::new_error(UNKNOWN_TID, "Unknown transfer ID");
+                let _ = self.socket.send_to(&err.serialize(), from);
+                continue;
+            }
+            let packet = match Packet::deserialize(&self.buf[..len]) {
+                Ok(packet) => packet,
+                // Too short to be any packet a server sends
+                Err(packet::Error::Truncated) => continue,
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+            self.peer = from;
+            self.locked = true;
+
+            return Ok(packet);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{self, Write};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
+
+    use super::{get, get_with, put, put_with, Cancelled, ClientConfig};
+    use crate::server::{Config, Server};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("tftp-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&path).unwrap();
+
+        path
+    }
+
+    fn start_server(config: Config) -> (Arc<Server>, JoinHandle<io::Result<()>>) {
+        let server = Arc::new(Server::bind("127.0.0.1:0", config).unwrap());
+
+        let running = server.clone();
+        let handle = thread::spawn(move || running.run());
+
+        (server, handle)
+    }
+
+    fn config() -> ClientConfig {
+        ClientConfig {
+            bind: Some("127.0.0.1:0".parse().unwrap()),
+            ..ClientConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_put_then_get() {
+        let root = temp_dir("client");
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let contents: Vec<u8> = (0..1300).map(|i| i as u8).collect();
+        let sent = put_with(&config(), addr, "name.bin", &contents[..]).unwrap();
+        assert_eq!(sent, 1300);
+        assert_eq!(fs::read(root.join("name.bin")).unwrap(), contents);
+
+        let mut received = Vec::new();
+        let size = get_with(&config(), addr, "name.bin", &mut received).unwrap();
+        assert_eq!(size, 1300);
+        assert_eq!(received, contents);
+
+        let missing = get_with(&config(), addr, "missing.bin", Vec::new());
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        server.shutdown_handle().shutdown();
+        handle.join().unwrap().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_default_bind_reaches_ipv6_server() {
+        let root = temp_dir("client_ipv6");
+        let config = Config {
+            root: root.clone(),
+            ..Config::default()
+        };
+        let server = Arc::new(Server::bind("[::1]:0", config).unwrap());
+        let running = server.clone();
+        let handle = thread::spawn(move || running.run());
+        let addr = server.local_addr().unwrap();
+
+        // Nothing bound, so the socket follows the server's family
+        assert_eq!(put(addr, "v6.txt", &b"over v6"[..]).unwrap(), 7);
+        let mut received = Vec::new();
+        assert_eq!(get(addr, "v6.txt", &mut received).unwrap(), 7);
+        assert_eq!(received, b"over v6");
+
+        server.shutdown_handle().shutdown();
+        handle.join().unwrap().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_get_times_out_on_slow_server() {
+        let root = temp_dir("client_slow");
+        fs::write(root.join("name.txt"), b"slow").unwrap();
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            artificial_delay: Duration::from_millis(300),
+            // So the abandoned transfers don't hold up shutdown
+            max_transfer_duration: Some(Duration::from_millis(100)),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let config = ClientConfig {
+            timeout: Duration::from_millis(50),
+            retries: 1,
+            ..config()
+        };
+        let res = get_with(&config, addr, "name.txt", Vec::new());
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        server.shutdown_handle().shutdown();
+        handle.join().unwrap().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Cancels the transfer it's written to once it's been given anything
+    struct CancelOnWrite {
+        written: Vec<u8>,
+        cancel: Arc<AtomicBool>,
+    }
+
+    impl Write for CancelOnWrite {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            self.cancel.store(true, Ordering::Relaxed);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_cancel_get() {
+        let root = temp_dir("client_cancel");
+        fs::write(root.join("name.bin"), vec![b'x'; 4096]).unwrap();
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let config = ClientConfig {
+            cancel: Some(cancel.clone()),
+            ..config()
+        };
+        let mut out = CancelOnWrite {
+            written: Vec::new(),
+            cancel,
+        };
+        let err = get_with(&config, addr, "name.bin", &mut out).unwrap_err();
+        assert!(err.get_ref().is_some_and(|e| e.is::<Cancelled>()));
+        assert_eq!(out.written.len(), 512);
+
+        // The server was told, so it isn't left waiting on an ACK
+        let start = Instant::now();
+        while !server.active_transfers().is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        server.shutdown_handle().shutdown();
+        handle.join().unwrap().unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1 +1,7 @@
+#[cfg(feature = "tokio")]
+pub mod async_server;
+pub mod client;
+pub mod metrics;
+pub mod netascii;
 pub mod packet;
+pub mod server;
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+// Counters
+pub const TRANSFERS_STARTED: &str = "tftp_transfers_started_total";
+pub const TRANSFERS_COMPLETED: &str = "tftp_transfers_completed_total";
+pub const TRANSFERS_FAILED: &str = "tftp_transfers_failed_total";
+pub const BYTES_SENT: &str = "tftp_bytes_sent_total";
+pub const BYTES_RECEIVED: &str = "tftp_bytes_received_total";
+
+// Gauges
+pub const ACTIVE_TRANSFERS: &str = "tftp_active_transfers";
+
+/// Where the server reports what it's doing, named by the constants in this
+/// module. Implement it to feed whatever metrics system is in use, or use
+/// [`InMemoryMetrics`] and scrape it.
+pub trait Metrics: Send + Sync {
+    /// Adds `n` to the counter `name`
+    fn increment(&self, name: &'static str, n: u64);
+    /// Sets the gauge `name` to its current `value`
+    fn observe(&self, name: &'static str, value: u64);
+}
+
+/// Keeps the latest value of every metric in memory
+#[derive(Default)]
+pub struct InMemoryMetrics {
+    values: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl InMemoryMetrics {
+    /// The value of `name`, which is 0 until it's first reported
+    pub fn get(&self, name: &str) -> u64 {
+        self.values.lock().unwrap().get(name).copied().unwrap_or(0)
+    }
+
+    /// Every metric reported so far, one `name value` line each, as
+    /// Prometheus expects from a scrape
+    pub fn render(&self) -> String {
+        let mut res = String::new();
+        for (name, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(res, "{} {}", name, value);
+        }
+
+        res
+    }
+}
+
+impl Metrics for InMemoryMetrics {
+    fn increment(&self, name: &'static str, n: u64) {
+        *self.values.lock().unwrap().entry(name).or_insert(0) += n;
+    }
+
+    fn observe(&self, name: &'static str, value: u64) {
+        self.values.lock().unwrap().insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{InMemoryMetrics, Metrics, ACTIVE_TRANSFERS, BYTES_SENT, TRANSFERS_STARTED};
+
+    #[test]
+    fn test_in_memory_metrics() {
+        let metrics = InMemoryMetrics::default();
+        assert_eq!(metrics.get(BYTES_SENT), 0);
+
+        metrics.increment(BYTES_SENT, 512);
+        metrics.increment(BYTES_SENT, 88);
+        metrics.increment(TRANSFERS_STARTED, 1);
+        metrics.observe(ACTIVE_TRANSFERS, 3);
+        metrics.observe(ACTIVE_TRANSFERS, 2);
+
+        assert_eq!(metrics.get(BYTES_SENT), 600);
+        assert_eq!(metrics.get(ACTIVE_TRANSFERS), 2);
+        assert_eq!(
+            metrics.render(),
+            "tftp_active_transfers 2\n\
+             tftp_bytes_sent_total 600\n\
+             tftp_transfers_started_total 1\n"
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::io::{self, Read};
+
+/// Converts a byte stream to netascii on the fly, as described in RFC 764:
+/// LF becomes CR LF and a bare CR becomes CR NUL.
+///
+/// Since the output is longer than the input, `read` keeps pulling from the
+/// inner reader until `buf` is full or the inner reader is exhausted, so a
+/// short read always means EOF. Wrap unbuffered sources in a `BufReader`, as
+/// the inner reader is consumed a byte at a time.
+pub struct NetAsciiReader<R> {
+    inner: R,
+    // Second half of an expanded pair that didn't fit in the last `buf`
+    pending: Option<u8>,
+}
+
+impl<R: Read> NetAsciiReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            pending: None,
+        }
+    }
+}
+
+impl<R: Read> Read for NetAsciiReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+
+        while n < buf.len() {
+            if let Some(b) = self.pending.take() {
+                buf[n] = b;
+                n += 1;
+                continue;
+            }
+
+            let mut byte = [0];
+            if self.inner.read(&mut byte)? == 0 {
+                break;
+            }
+
+            buf[n] = match byte[0] {
+                b'\n' => {
+                    self.pending = Some(b'\n');
+                    b'\r'
+                }
+                b'\r' => {
+                    self.pending = Some(0);
+                    b'\r'
+                }
+                b => b,
+            };
+            n += 1;
+        }
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use super::NetAsciiReader;
+
+    fn convert(input: &[u8], chunk: usize) -> Vec<u8> {
+        let mut reader = NetAsciiReader::new(input);
+        let mut res = Vec::new();
+
+        let mut buf = vec![0; chunk];
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            res.extend_from_slice(&buf[..n]);
+
+            if n < chunk {
+                return res;
+            }
+        }
+    }
+
+    #[test]
+    fn test_line_endings() {
+        assert_eq!(convert(b"a\nb\r\nc\rd", 512), b"a\r\nb\r\0\r\nc\r\0d");
+    }
+
+    #[test]
+    fn test_pair_split_across_reads() {
+        // Every read ends halfway through an expanded newline
+        assert_eq!(convert(b"\n\n\n", 3), b"\r\n\r\n\r\n");
+    }
+}
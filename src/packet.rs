@@ -1,4 +1,5 @@
-use std::io::{BufReader, Cursor, Read};
+use std::borrow::Cow;
+use std::io::{self, Cursor, Read};
 
 #[derive(Debug, PartialEq)]
 pub enum Mode {
@@ -8,35 +9,103 @@ pub enum Mode {
 }
 
 impl Mode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::NetAscii => "netascii",
+            Mode::Octet => "octet",
+            Mode::Mail => "mail",
+        }
+    }
+
     pub fn encode(&self) -> &[u8] {
-        todo!()
+        self.as_str().as_bytes()
     }
 }
 
+/// Why a packet couldn't be deserialized
+///
+/// More variants may be added as the parser learns to reject more malformed
+/// packets, so matches need a catch-all arm:
+///
+/// ```
+/// use tftp::packet::{Error, Packet};
+///
+/// match Packet::deserialize(&[0, 9]) {
+///     Err(Error::InvalidOpcode(op_code)) => assert_eq!(op_code, 9),
+///     Err(e) => panic!("unexpected error: {}", e),
+///     Ok(_) => panic!("opcode 9 was accepted"),
+/// }
+/// ```
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
-    InvalidOpcode,
-    NoZeroByte,
+    /// The datagram ends before the packet's opcode, or before the block
+    /// number or error code of a DATA, ACK or ERROR
+    Truncated,
+    InvalidOpcode(u16),
+    /// A string starting at `offset` bytes into the packet isn't terminated
+    NoZeroByte {
+        op_code: u16,
+        offset: usize,
+    },
+    InvalidFilename,
+    /// A RRQ/WRQ ends right after its filename, or its mode is empty
+    MissingMode,
+    /// A RRQ/WRQ asks for a mode other than netascii, octet or mail
+    InvalidMode(String),
+    /// An option was given twice with different values
+    ConflictingOption(String),
+    /// There are more than `MAX_OPTIONS` options
+    TooManyOptions,
+    /// The options take more than `MAX_OPTIONS_LEN` bytes
+    OptionsTooLong,
+    /// The string starting at `offset` bytes into the packet isn't UTF-8
+    NotUtf8 {
+        op_code: u16,
+        offset: usize,
+    },
+    /// Reading the packet failed
+    Io(io::Error),
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Error::InvalidOpcode => write!(f, "invalid opcode"),
-            Error::NoZeroByte => write!(f, "couldn't find zero byte"),
+            Error::Truncated => write!(f, "packet is truncated"),
+            Error::InvalidOpcode(op_code) => write!(f, "invalid opcode {}", op_code),
+            Error::NoZeroByte { op_code, offset } => write!(
+                f,
+                "couldn't find zero byte after offset {} (opcode {})",
+                offset, op_code
+            ),
+            Error::InvalidFilename => write!(f, "filename contains control characters"),
+            Error::MissingMode => write!(f, "request has no transfer mode"),
+            Error::InvalidMode(mode) => write!(f, "unknown transfer mode {:?}", mode),
+            Error::ConflictingOption(name) => write!(f, "option {} given conflicting values", name),
+            Error::TooManyOptions => write!(f, "more than {} options", MAX_OPTIONS),
+            Error::OptionsTooLong => write!(f, "options longer than {} bytes", MAX_OPTIONS_LEN),
+            Error::NotUtf8 { op_code, offset } => write!(
+                f,
+                "string at offset {} isn't valid UTF-8 (opcode {})",
+                offset, op_code
+            ),
+            Error::Io(e) => write!(f, "couldn't read packet: {}", e),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
-impl From<&str> for Mode {
-    fn from(s: &str) -> Self {
+impl TryFrom<&str> for Mode {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self, Error> {
         match s.to_lowercase().as_str() {
-            "netascii" => Mode::NetAscii,
-            "octet" => Mode::Octet,
-            "mail" => Mode::Mail,
-            _ => panic!(),
+            "netascii" => Ok(Mode::NetAscii),
+            "octet" => Ok(Mode::Octet),
+            "mail" => Ok(Mode::Mail),
+            "" => Err(Error::MissingMode),
+            _ => Err(Error::InvalidMode(s.to_owned())),
         }
     }
 }
@@ -47,6 +116,7 @@ pub const WRITE_OPCODE: u16 = 2;
 pub const DATA_OPCODE: u16 = 3;
 pub const ACK_OPCODE: u16 = 4;
 pub const ERROR_OPCODE: u16 = 5;
+pub const OACK_OPCODE: u16 = 6;
 
 // Errors
 pub const SEE_MSG: u16 = 0;
@@ -57,6 +127,18 @@ pub const ILLEGAL_OP: u16 = 4;
 pub const UNKNOWN_TID: u16 = 5;
 pub const FILE_EXISTS: u16 = 6;
 pub const NO_USER: u16 = 7;
+pub const OPTION_NEGOTIATION: u16 = 8;
+
+/// Block size used unless a different one is negotiated
+pub const DEFAULT_BLKSIZE: usize = 512;
+/// Range of block sizes the blksize option may negotiate (RFC 2348)
+pub const MIN_BLKSIZE: usize = 8;
+pub const MAX_BLKSIZE: usize = 65464;
+
+/// Most options a RRQ/WRQ or OACK may carry, and most bytes they may take
+/// between them, so a request can't make the parser do unbounded work
+pub const MAX_OPTIONS: usize = 64;
+pub const MAX_OPTIONS_LEN: usize = 4096;
 
 /// https://www.rfc-editor.org/rfc/rfc1350
 pub enum Packet {
@@ -66,10 +148,15 @@ pub enum Packet {
     /// | Opcode |  Filename  |   0  |    Mode    |   0  |
     ///  ------------------------------------------------
     /// Mode can be either "netascii", "octet" or "mail"
+    ///
+    /// The mode may be followed by options (RFC 2347), each a name and a value
+    /// terminated by a zero byte. Option names are case-insensitive, so they're
+    /// stored lowercased.
     Request {
         op_code: u16,
         file: String,
         mode: Mode,
+        options: Vec<(String, String)>,
     },
     /// DATA Packet
     ///  2 bytes     2 bytes      n bytes
@@ -82,7 +169,7 @@ pub enum Packet {
         block: u16,
         data: Vec<u8>,
 
-        // If its less than 512 bytes, it's the last data packet
+        // If its less than the block size, it's the last data packet
         len: usize,
     },
     /// ACK Packet
@@ -107,11 +194,31 @@ pub enum Packet {
     ///  5 Unknown transfer ID.
     ///  6 File already exists.
     ///  7 No such user.
-    Error { code: u16, msg: String },
+    ///  8 Option negotiation failed (RFC 2347).
+    Error { code: u16, msg: Cow<'static, str> },
+    /// OACK Packet (RFC 2347)
+    ///  2 bytes    string    1 byte   string   1 byte
+    ///  ----------------------------------------------
+    /// | Opcode |   opt1   |   0  |  value1  |   0  | ...
+    ///  ----------------------------------------------
+    /// Acknowledges the options of a RRQ/WRQ the server accepted.
+    OAck { options: Vec<(String, String)> },
 }
 
 impl Packet {
+    /// Parses a datagram. The packet owns everything it holds, copied out of
+    /// `bytes`, so a receive buffer can be reused as soon as this returns.
+    ///
+    /// Some clients pad their datagrams, so bytes after the end of an ACK's
+    /// block number or an ERROR's message are ignored. A DATA packet has no
+    /// end but the datagram's, so whatever follows its header is data.
+    /// Requests and OACKs end with their last option, so only zero padding
+    /// may follow them; anything else is read as another option and fails
+    /// to parse.
     pub fn deserialize(bytes: &[u8]) -> Result<Packet, Error> {
+        if bytes.len() < 2 {
+            return Err(Error::Truncated);
+        }
         let op_code = u16::from_be_bytes([bytes[0], bytes[1]]);
 
         let packet = match op_code {
@@ -120,21 +227,43 @@ impl Packet {
             DATA_OPCODE => parse_data(bytes)?,
             ACK_OPCODE => parse_ack(bytes)?,
             ERROR_OPCODE => parse_error(bytes)?,
-            _ => Err(Error::InvalidOpcode)?,
+            OACK_OPCODE => parse_oack(bytes)?,
+            _ => Err(Error::InvalidOpcode(op_code))?,
         };
 
         Ok(packet)
     }
 
+    /// Reads a packet from the whole of `reader`, which holds exactly one
+    /// datagram's worth, e.g. a frame split off by a codec. It's parsed as
+    /// `deserialize` would.
+    pub fn decode_from(reader: &mut impl Read) -> Result<Packet, Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(Error::Io)?;
+
+        Self::deserialize(&bytes)
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(self.serialized_len());
+        self.serialize_into(&mut res);
+
+        res
+    }
+
+    /// Same as `serialize`, but writes into a caller-owned buffer so it can be
+    /// reused across packets. The buffer is cleared first.
+    pub fn serialize_into(&self, res: &mut Vec<u8>) {
+        res.clear();
+        res.reserve(self.serialized_len());
+
         match self {
             Packet::Request {
                 op_code,
                 file,
                 mode,
+                options,
             } => {
-                let mut res: Vec<u8> = Vec::with_capacity(30);
-
                 let op_code = op_code.to_be_bytes();
                 res.extend_from_slice(&op_code);
 
@@ -146,15 +275,13 @@ impl Packet {
                 res.extend_from_slice(mode);
                 res.push(0);
 
-                res
+                serialize_options(options, res);
             }
             Packet::Data {
                 block,
                 data,
                 len: _,
             } => {
-                let mut res: Vec<u8> = Vec::with_capacity(516);
-
                 let op_code = DATA_OPCODE.to_be_bytes();
                 res.extend_from_slice(&op_code);
 
@@ -162,23 +289,15 @@ impl Packet {
                 res.extend_from_slice(&block);
 
                 res.extend_from_slice(data);
-
-                res
             }
             Packet::Ack { block } => {
-                let mut res: Vec<u8> = Vec::with_capacity(4);
-
                 let op_code = ACK_OPCODE.to_be_bytes();
                 res.extend_from_slice(&op_code);
 
                 let block = block.to_be_bytes();
                 res.extend_from_slice(&block);
-
-                res
             }
             Packet::Error { code, msg } => {
-                let mut res: Vec<u8> = Vec::with_capacity(30);
-
                 let op_code = ERROR_OPCODE.to_be_bytes();
                 res.extend_from_slice(&op_code);
 
@@ -188,21 +307,124 @@ impl Packet {
                 res.extend_from_slice(&code);
 
                 let msg = msg.as_bytes();
-                res.extend_from_slice(&msg);
+                res.extend_from_slice(msg);
                 res.push(0);
+            }
+            Packet::OAck { options } => {
+                let op_code = OACK_OPCODE.to_be_bytes();
+                res.extend_from_slice(&op_code);
 
-                res
+                serialize_options(options, res);
             }
         }
     }
 
-    pub fn new_error(code: u16, msg: &str) -> Self {
+    /// Number of bytes `serialize` produces for this packet
+    pub fn serialized_len(&self) -> usize {
+        match self {
+            Packet::Request {
+                file,
+                mode,
+                options,
+                ..
+            } => 2 + file.len() + 1 + mode.as_str().len() + 1 + options_len(options),
+            Packet::Data { data, .. } => 4 + data.len(),
+            Packet::Ack { .. } => 4,
+            Packet::Error { msg, .. } => 4 + msg.len() + 1,
+            Packet::OAck { options } => 2 + options_len(options),
+        }
+    }
+
+    /// Builds an ERROR packet. A `&'static str` message, like most of the
+    /// server's, is borrowed rather than copied; a `String` is moved in.
+    pub fn new_error(code: u16, msg: impl Into<Cow<'static, str>>) -> Self {
         Self::Error {
             code,
-            msg: msg.to_owned(),
+            msg: msg.into(),
+        }
+    }
+
+    /// Builds an ERROR packet from an I/O error, mapping its kind onto the
+    /// closest TFTP error code and using its description as the message.
+    pub fn from_io_error(e: &io::Error) -> Self {
+        let code = match e.kind() {
+            io::ErrorKind::NotFound => FILE_NOT_FOUND,
+            io::ErrorKind::PermissionDenied => ACCESS_VIOLATION,
+            io::ErrorKind::StorageFull => DISK_FULL,
+            io::ErrorKind::AlreadyExists => FILE_EXISTS,
+            _ => SEE_MSG,
+        };
+
+        Self::Error {
+            code,
+            msg: e.to_string().into(),
+        }
+    }
+
+    /// The transfer mode of a RRQ/WRQ
+    pub fn mode(&self) -> Option<&Mode> {
+        match self {
+            Packet::Request { mode, .. } => Some(mode),
+            _ => None,
+        }
+    }
+
+    /// Whether this is an ERROR packet, which ends any transfer it's part of
+    pub fn is_error(&self) -> bool {
+        matches!(self, Packet::Error { .. })
+    }
+
+    /// The error code of an ERROR packet
+    pub fn error_code(&self) -> Option<u16> {
+        match self {
+            Packet::Error { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// The message of an ERROR packet
+    pub fn error_msg(&self) -> Option<&str> {
+        match self {
+            Packet::Error { msg, .. } => Some(msg.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// The opcode, filename and mode of a RRQ/WRQ
+    pub fn as_request(&self) -> Option<(u16, &str, &Mode)> {
+        match self {
+            Packet::Request {
+                op_code,
+                file,
+                mode,
+                ..
+            } => Some((*op_code, file, mode)),
+            _ => None,
+        }
+    }
+
+    /// The block number and payload of a DATA packet
+    pub fn as_data(&self) -> Option<(u16, &[u8])> {
+        match self {
+            Packet::Data { block, data, .. } => Some((*block, data)),
+            _ => None,
+        }
+    }
+
+    /// The block number of an ACK packet
+    pub fn as_ack(&self) -> Option<u16> {
+        match self {
+            Packet::Ack { block } => Some(*block),
+            _ => None,
         }
     }
 
+    /// Whether this is the DATA packet that ends a transfer, i.e. one that
+    /// carries less than a full block (possibly nothing at all)
+    pub fn is_final_data(&self, blksize: usize) -> bool {
+        matches!(self, Packet::Data { len, .. } if *len < blksize)
+    }
+
     pub fn new_data(block: u16, mut data: Vec<u8>, len: usize) -> Self {
         data.truncate(len);
         Self::Data { block, data, len }
@@ -211,62 +433,158 @@ impl Packet {
     pub fn new_ack(block: u16) -> Self {
         Self::Ack { block }
     }
+
+    /// Builds a RRQ or WRQ. Any opcode but `READ_OPCODE` and `WRITE_OPCODE`
+    /// is refused with `Error::InvalidOpcode`.
+    pub fn new_request(
+        op_code: u16,
+        file: impl Into<String>,
+        mode: Mode,
+        options: Vec<(String, String)>,
+    ) -> Result<Self, Error> {
+        if op_code != READ_OPCODE && op_code != WRITE_OPCODE {
+            return Err(Error::InvalidOpcode(op_code));
+        }
+
+        Ok(Self::Request {
+            op_code,
+            file: file.into(),
+            mode,
+            options,
+        })
+    }
 }
 
 fn parse_rwrq(bytes: &[u8], op_code: u16) -> Result<Packet, Error> {
-    let mut cursor = Cursor::new(&bytes[2..]);
+    let mut cursor = Cursor::new(bytes);
+    cursor.set_position(2);
+
+    let file = read_str(&mut cursor)?;
+    // Control characters have no business in a filename and only confuse
+    // logs and the filesystem
+    if file.chars().any(|c| c.is_ascii_control()) {
+        return Err(Error::InvalidFilename);
+    }
 
-    let file = read_until_zero_byte(&mut cursor)?;
-    let file = std::str::from_utf8(file).unwrap();
+    if cursor.position() as usize == bytes.len() {
+        return Err(Error::MissingMode);
+    }
+    let mode = read_str(&mut cursor)?;
+    let mode = Mode::try_from(mode)?;
 
-    let mode = read_until_zero_byte(&mut cursor)?;
-    let mode = std::str::from_utf8(mode).unwrap();
-    let mode: Mode = mode.into();
+    let options = parse_options(&mut cursor)?;
 
     Ok(Packet::Request {
         op_code,
         file: file.to_owned(),
         mode,
+        options,
     })
 }
 
 fn parse_data(bytes: &[u8]) -> Result<Packet, Error> {
+    if bytes.len() < 4 {
+        return Err(Error::Truncated);
+    }
     let block = u16::from_be_bytes([bytes[2], bytes[3]]);
 
-    let mut data = [0; 512];
-    let mut reader = BufReader::new(&bytes[4..]);
-    // TODO: handle error
-    let len = reader.read(&mut data).expect("ok");
-
-    let mut data = data.to_vec();
-    data.truncate(len);
+    // The datagram holds exactly one block, whatever the negotiated size
+    let data = bytes[4..].to_vec();
+    let len = data.len();
 
     Ok(Packet::Data { block, data, len })
 }
 
 fn parse_ack(bytes: &[u8]) -> Result<Packet, Error> {
+    if bytes.len() < 4 {
+        return Err(Error::Truncated);
+    }
     let block = u16::from_be_bytes([bytes[2], bytes[3]]);
 
     Ok(Packet::Ack { block })
 }
 
 fn parse_error(bytes: &[u8]) -> Result<Packet, Error> {
+    if bytes.len() < 4 {
+        return Err(Error::Truncated);
+    }
     let code = u16::from_le_bytes([bytes[2], bytes[3]]);
 
-    let mut cursor = Cursor::new(&bytes[4..]);
+    let mut cursor = Cursor::new(bytes);
+    cursor.set_position(4);
 
-    let msg = read_until_zero_byte(&mut cursor)?;
-    let msg = std::str::from_utf8(msg).unwrap();
+    let msg = read_str(&mut cursor)?;
 
     Ok(Packet::Error {
         code,
-        msg: msg.to_owned(),
+        msg: msg.to_owned().into(),
     })
 }
 
+fn parse_oack(bytes: &[u8]) -> Result<Packet, Error> {
+    let mut cursor = Cursor::new(bytes);
+    cursor.set_position(2);
+
+    let options = parse_options(&mut cursor)?;
+
+    Ok(Packet::OAck { options })
+}
+
+/// Reads name/value pairs until the end of the packet, or until the zero
+/// padding that follows it in a larger receive buffer.
+fn parse_options(cursor: &mut Cursor<&[u8]>) -> Result<Vec<(String, String)>, Error> {
+    let mut options = Vec::new();
+    let start = cursor.position();
+
+    for count in 0.. {
+        let pos = cursor.position() as usize;
+        if matches!(cursor.get_ref().get(pos), None | Some(0)) {
+            break;
+        }
+        if count == MAX_OPTIONS {
+            return Err(Error::TooManyOptions);
+        }
+
+        let name = read_str(cursor)?;
+
+        let value = read_str(cursor)?;
+        if (cursor.position() - start) as usize > MAX_OPTIONS_LEN {
+            return Err(Error::OptionsTooLong);
+        }
+
+        // Names are case-insensitive (RFC 2347), so "BLKSIZE" repeats
+        // "blksize". A repeat with the same value is dropped; one with
+        // another value leaves no telling which was meant.
+        let name = name.to_lowercase();
+        match options.iter().find(|(seen, _)| *seen == name) {
+            Some((_, seen)) if seen == value => {}
+            Some(_) => return Err(Error::ConflictingOption(name)),
+            None => options.push((name, value.to_owned())),
+        }
+    }
+
+    Ok(options)
+}
+
+fn options_len(options: &[(String, String)]) -> usize {
+    options
+        .iter()
+        .map(|(name, value)| name.len() + 1 + value.len() + 1)
+        .sum()
+}
+
+fn serialize_options(options: &[(String, String)], res: &mut Vec<u8>) {
+    for (name, value) in options {
+        res.extend_from_slice(name.as_bytes());
+        res.push(0);
+        res.extend_from_slice(value.as_bytes());
+        res.push(0);
+    }
+}
+
 fn read_until_zero_byte<'a>(cursor: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
     let start = cursor.position() as usize;
-    let end = cursor.get_ref().len() - 1;
+    let end = cursor.get_ref().len();
 
     for i in start..end {
         if cursor.get_ref()[i] == b'\0' {
@@ -276,12 +594,32 @@ fn read_until_zero_byte<'a>(cursor: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], E
         }
     }
 
-    Err(Error::NoZeroByte)
+    Err(Error::NoZeroByte {
+        op_code: u16::from_be_bytes([cursor.get_ref()[0], cursor.get_ref()[1]]),
+        offset: start,
+    })
+}
+
+/// Reads a zero-terminated string, which must be UTF-8
+fn read_str<'a>(cursor: &mut Cursor<&'a [u8]>) -> Result<&'a str, Error> {
+    let start = cursor.position() as usize;
+    let bytes = read_until_zero_byte(cursor)?;
+
+    std::str::from_utf8(bytes).map_err(|_| Error::NotUtf8 {
+        op_code: u16::from_be_bytes([cursor.get_ref()[0], cursor.get_ref()[1]]),
+        offset: start,
+    })
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Mode, Packet, READ_OPCODE, WRITE_OPCODE};
+    use std::borrow::Cow;
+    use std::io;
+
+    use super::{
+        Error, Mode, Packet, ACCESS_VIOLATION, ERROR_OPCODE, FILE_EXISTS, FILE_NOT_FOUND,
+        MAX_OPTIONS, MAX_OPTIONS_LEN, READ_OPCODE, SEE_MSG, WRITE_OPCODE,
+    };
 
     fn test_rwrq(rq: &[u8], exp_op_code: u16, exp_file: &str, exp_mode: Mode) {
         let packet = Packet::deserialize(rq).unwrap();
@@ -291,6 +629,7 @@ mod test {
                 op_code,
                 file,
                 mode,
+                options: _,
             } => {
                 assert_eq!(
                     op_code, exp_op_code,
@@ -326,6 +665,205 @@ mod test {
         test_rwrq(wrq, WRITE_OPCODE, "main.rs", Mode::NetAscii);
     }
 
+    #[test]
+    fn test_parse_rrq_options() {
+        // read, boot.img, octet, blksize=1432, RANGE=0-10
+        let rrq = b"\x00\x01boot.img\0octet\0blksize\x001432\0RANGE\x000-10\0\0";
+
+        match Packet::deserialize(rrq).unwrap() {
+            Packet::Request { options, .. } => {
+                assert_eq!(
+                    options,
+                    [
+                        ("blksize".to_owned(), "1432".to_owned()),
+                        ("range".to_owned(), "0-10".to_owned())
+                    ]
+                );
+            }
+            _ => panic!("did not get expected packet: Request"),
+        }
+    }
+
+    #[test]
+    fn test_parse_rrq_duplicate_options() {
+        let rrq = b"\x00\x01boot.img\0octet\0blksize\x001432\0BLKSIZE\x001432\0\0";
+
+        match Packet::deserialize(rrq).unwrap() {
+            Packet::Request { options, .. } => {
+                assert_eq!(options, [("blksize".to_owned(), "1432".to_owned())]);
+            }
+            _ => panic!("did not get expected packet: Request"),
+        }
+
+        let rrq = b"\x00\x01boot.img\0octet\0blksize\x001432\0blksize\x00512\0\0";
+
+        match Packet::deserialize(rrq) {
+            Err(Error::ConflictingOption(name)) => assert_eq!(name, "blksize"),
+            _ => panic!("expected Error::ConflictingOption"),
+        }
+    }
+
+    #[test]
+    fn test_oack_round_trip() {
+        let options = vec![("range".to_owned(), "100-200".to_owned())];
+        let bytes = Packet::OAck {
+            options: options.clone(),
+        }
+        .serialize();
+
+        assert_eq!(bytes, b"\x00\x06range\x00100-200\0");
+
+        // As received into a zero-padded buffer
+        let mut buf = [0; 64];
+        buf[..bytes.len()].copy_from_slice(&bytes);
+
+        match Packet::deserialize(&buf).unwrap() {
+            Packet::OAck { options: got } => assert_eq!(got, options),
+            _ => panic!("did not get expected packet: OAck"),
+        }
+    }
+
+    #[test]
+    fn test_parse_rrq_unterminated_mode() {
+        // read, main.rs, octet without its terminator
+        let rrq = b"\x00\x01main.rs\0octet";
+
+        match Packet::deserialize(rrq) {
+            Err(Error::NoZeroByte { op_code, offset }) => {
+                assert_eq!(op_code, READ_OPCODE);
+                assert_eq!(offset, 10);
+            }
+            _ => panic!("expected Error::NoZeroByte"),
+        }
+    }
+
+    #[test]
+    fn test_parse_rrq_missing_mode() {
+        let rrq = b"\x00\x01main.rs\0";
+
+        assert!(matches!(Packet::deserialize(rrq), Err(Error::MissingMode)));
+    }
+
+    #[test]
+    fn test_parse_rrq_empty_mode() {
+        let rrq = b"\x00\x01main.rs\0\0";
+
+        assert!(matches!(Packet::deserialize(rrq), Err(Error::MissingMode)));
+    }
+
+    #[test]
+    fn test_parse_rrq_invalid_mode() {
+        let rrq = b"\x00\x01main.rs\0binary\0";
+
+        match Packet::deserialize(rrq) {
+            Err(Error::InvalidMode(mode)) => assert_eq!(mode, "binary"),
+            _ => panic!("expected Error::InvalidMode"),
+        }
+    }
+
+    #[test]
+    fn test_parse_rrq_option_limits() {
+        let rrq = |options: &[(String, String)]| {
+            let mut rrq = b"\x00\x01main.rs\0octet\0".to_vec();
+            for (name, value) in options {
+                rrq.extend_from_slice(format!("{}\0{}\0", name, value).as_bytes());
+            }
+            rrq
+        };
+        let numbered = |n| {
+            (0..n)
+                .map(|i| (format!("opt{}", i), "1".to_owned()))
+                .collect::<Vec<_>>()
+        };
+
+        match Packet::deserialize(&rrq(&numbered(MAX_OPTIONS))).unwrap() {
+            Packet::Request { options, .. } => assert_eq!(options.len(), MAX_OPTIONS),
+            _ => panic!("expected a request"),
+        }
+        assert!(matches!(
+            Packet::deserialize(&rrq(&numbered(10_000))),
+            Err(Error::TooManyOptions)
+        ));
+
+        let long = [("pad".to_owned(), "x".repeat(MAX_OPTIONS_LEN))];
+        assert!(matches!(
+            Packet::deserialize(&rrq(&long)),
+            Err(Error::OptionsTooLong)
+        ));
+    }
+
+    #[test]
+    fn test_parse_rrq_invalid_utf8() {
+        // read, a Latin-1 "café.txt", octet
+        let rrq = b"\x00\x01caf\xe9.txt\0octet\0";
+
+        match Packet::deserialize(rrq) {
+            Err(Error::NotUtf8 { op_code, offset }) => {
+                assert_eq!(op_code, READ_OPCODE);
+                assert_eq!(offset, 2);
+            }
+            _ => panic!("expected Error::NotUtf8"),
+        }
+    }
+
+    #[test]
+    fn test_parse_rrq_control_character() {
+        // read, main\n.rs, octet
+        let rrq = &[
+            0x00, 0x01, b'm', b'a', b'i', b'n', b'\n', b'.', b'r', b's', 0x00, b'o', b'c', b't',
+            b'e', b't', 0x00, /**/ 0x00,
+        ];
+
+        match Packet::deserialize(rrq) {
+            Err(Error::InvalidFilename) => (),
+            _ => panic!("expected Error::InvalidFilename"),
+        }
+    }
+
+    #[test]
+    fn test_mode() {
+        // read, boot.img, octet
+        let rrq = &[
+            0x00, 0x01, b'b', b'o', b'o', b't', b'.', b'i', b'm', b'g', 0x00, b'o', b'c', b't',
+            b'e', b't', 0x00, /**/ 0x00,
+        ];
+
+        let packet = Packet::deserialize(rrq).unwrap();
+        assert_eq!(packet.mode(), Some(&Mode::Octet));
+
+        assert_eq!(Packet::new_ack(1).mode(), None);
+    }
+
+    #[test]
+    fn test_serialized_len() {
+        let options = vec![("blksize".to_owned(), "1432".to_owned())];
+        let packets = [
+            Packet::Request {
+                op_code: READ_OPCODE,
+                file: "boot.img".to_owned(),
+                mode: Mode::NetAscii,
+                options: options.clone(),
+            },
+            Packet::new_data(1, vec![0; 100], 100),
+            Packet::new_data(2, Vec::new(), 0),
+            Packet::new_ack(3),
+            Packet::new_error(FILE_NOT_FOUND, "File not found"),
+            Packet::OAck { options },
+        ];
+
+        for packet in packets {
+            assert_eq!(packet.serialized_len(), packet.serialize().len());
+        }
+    }
+
+    #[test]
+    fn test_mode_as_str() {
+        assert_eq!(Mode::NetAscii.as_str(), "netascii");
+        assert_eq!(Mode::Octet.as_str(), "octet");
+        assert_eq!(Mode::Mail.as_str(), "mail");
+        assert_eq!(Mode::Octet.encode(), b"octet");
+    }
+
     #[test]
     fn test_parse_data() {
         let data = &[
@@ -345,6 +883,74 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_decode_from() {
+        let mut frame = io::Cursor::new(b"\x00\x03\x00\x07abc".to_vec());
+        match Packet::decode_from(&mut frame).unwrap() {
+            Packet::Data { block, data, len } => {
+                assert_eq!(block, 7);
+                assert_eq!(data, b"abc");
+                assert_eq!(len, 3);
+            }
+            _ => panic!("expected DATA"),
+        }
+
+        let mut frame = io::Cursor::new(b"\x00\x01a.txt\x00octet\x00".to_vec());
+        let rrq = Packet::decode_from(&mut frame).unwrap();
+        assert_eq!(rrq.as_request(), Some((READ_OPCODE, "a.txt", &Mode::Octet)));
+
+        let mut short = io::Cursor::new(vec![0x00]);
+        assert!(matches!(
+            Packet::decode_from(&mut short),
+            Err(Error::Truncated)
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_truncated() {
+        let inputs: [&[u8]; 8] = [
+            &[],
+            &[0],
+            &[0, 3],
+            &[0, 3, 0],
+            &[0, 4],
+            &[0, 4, 0],
+            &[0, 5],
+            &[0, 5, 1],
+        ];
+
+        for bytes in inputs {
+            assert!(
+                matches!(Packet::deserialize(bytes), Err(Error::Truncated)),
+                "{:?}",
+                bytes
+            );
+        }
+    }
+
+    #[test]
+    fn test_deserialize_outlives_buffer() {
+        let mut buf = b"\x00\x01name.txt\x00octet\x00blksize\x001024\x00".to_vec();
+        let rrq = Packet::deserialize(&buf).unwrap();
+        let mut data = vec![0x00, 0x03, 0x00, 0x01, b'a', b'b', b'c'];
+        let block = Packet::deserialize(&data).unwrap();
+
+        buf.fill(b'x');
+        data.fill(b'x');
+
+        assert_eq!(
+            rrq.as_request(),
+            Some((READ_OPCODE, "name.txt", &Mode::Octet))
+        );
+        match rrq {
+            Packet::Request { options, .. } => {
+                assert_eq!(options, [("blksize".to_owned(), "1024".to_owned())])
+            }
+            _ => panic!("did not get expected packet: Request"),
+        }
+        assert_eq!(block.as_data(), Some((1, &b"abc"[..])));
+    }
+
     #[test]
     fn test_parse_ack() {
         let data = &[0x00, 0x04, 0x00, 0x00];
@@ -359,6 +965,39 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_trailing_bytes() {
+        let ack = Packet::deserialize(&[0x00, 0x04, 0x00, 0x07, b'x', b'y']).unwrap();
+        assert_eq!(ack.as_ack(), Some(7));
+
+        let error = Packet::deserialize(b"\x00\x05\x01\x00oops\0xy").unwrap();
+        assert_eq!(error.error_msg(), Some("oops"));
+
+        let data = Packet::deserialize(&[0x00, 0x03, 0x00, 0x01, b'x', b'y']).unwrap();
+        assert_eq!(data.as_data(), Some((1, &b"xy"[..])));
+
+        let padded = Packet::deserialize(b"\x00\x01name\0octet\0\0\0\0").unwrap();
+        assert_eq!(
+            padded.as_request(),
+            Some((READ_OPCODE, "name", &Mode::Octet))
+        );
+        assert!(matches!(
+            Packet::deserialize(b"\x00\x01name\0octet\0xy"),
+            Err(Error::NoZeroByte { .. })
+        ));
+
+        match Packet::deserialize(b"\x00\x06tsize\x0042\0\0\0").unwrap() {
+            Packet::OAck { options } => {
+                assert_eq!(options, [("tsize".to_owned(), "42".to_owned())])
+            }
+            _ => panic!("did not get expected packet: OAck"),
+        }
+        assert!(matches!(
+            Packet::deserialize(b"\x00\x06tsize\x0042\0xy"),
+            Err(Error::NoZeroByte { .. })
+        ));
+    }
+
     #[test]
     fn test_parse_error() {
         let data = &[
@@ -375,4 +1014,139 @@ mod test {
             _ => panic!("did not get expected packet: Error"),
         }
     }
+
+    #[test]
+    fn test_error_code_little_endian() {
+        // Error codes go out little endian for the client mentioned in
+        // `serialize`, not network order as the RFC has it
+        let bytes = Packet::new_error(FILE_NOT_FOUND, "x").serialize();
+        assert_eq!(bytes, [0x00, 0x05, 0x01, 0x00, b'x', 0x00]);
+
+        match Packet::deserialize(&bytes).unwrap() {
+            Packet::Error { code, msg } => {
+                assert_eq!(code, FILE_NOT_FOUND);
+                assert_eq!(msg, "x");
+            }
+            _ => panic!("did not get expected packet: Error"),
+        }
+    }
+
+    fn test_io_error(kind: io::ErrorKind, exp_code: u16) {
+        let e = io::Error::new(kind, "oops");
+
+        match Packet::from_io_error(&e) {
+            Packet::Error { code, msg } => {
+                assert_eq!(code, exp_code, "Expected: {}\nGot: {}", exp_code, code);
+                assert_eq!(msg, "oops");
+            }
+            _ => panic!("did not get expected packet: Error"),
+        }
+    }
+
+    #[test]
+    fn test_from_io_error_not_found() {
+        test_io_error(io::ErrorKind::NotFound, FILE_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_from_io_error_permission_denied() {
+        test_io_error(io::ErrorKind::PermissionDenied, ACCESS_VIOLATION);
+    }
+
+    #[test]
+    fn test_from_io_error_already_exists() {
+        test_io_error(io::ErrorKind::AlreadyExists, FILE_EXISTS);
+    }
+
+    #[test]
+    fn test_serialize_into_dirty_buffer() {
+        let mut buf = vec![0xff; 600];
+
+        let packets = [
+            Packet::new_data(1, b"hello world".to_vec(), 11),
+            Packet::new_ack(7),
+            Packet::new_error(FILE_NOT_FOUND, "File not found"),
+        ];
+
+        for packet in packets {
+            packet.serialize_into(&mut buf);
+            assert_eq!(buf, packet.serialize());
+        }
+    }
+
+    #[test]
+    fn test_new_error_static_message() {
+        match Packet::new_error(FILE_NOT_FOUND, "File not found") {
+            Packet::Error { code, msg } => {
+                assert_eq!(code, FILE_NOT_FOUND);
+                assert!(matches!(msg, Cow::Borrowed("File not found")));
+            }
+            _ => panic!("expected Packet::Error"),
+        }
+
+        let owned = Packet::new_error(SEE_MSG, format!("{} left", 3));
+        assert_eq!(owned.error_msg(), Some("3 left"));
+    }
+
+    #[test]
+    fn test_as_accessors() {
+        let rrq = Packet::Request {
+            op_code: READ_OPCODE,
+            file: "boot.img".to_owned(),
+            mode: Mode::Octet,
+            options: Vec::new(),
+        };
+        let data = Packet::new_data(7, b"payload".to_vec(), 7);
+        let ack = Packet::new_ack(9);
+
+        assert_eq!(
+            rrq.as_request(),
+            Some((READ_OPCODE, "boot.img", &Mode::Octet))
+        );
+        assert_eq!(data.as_request(), None);
+
+        assert_eq!(data.as_data(), Some((7, &b"payload"[..])));
+        assert_eq!(ack.as_data(), None);
+
+        assert_eq!(ack.as_ack(), Some(9));
+        assert_eq!(rrq.as_ack(), None);
+    }
+
+    #[test]
+    fn test_new_request() {
+        let wrq = Packet::new_request(WRITE_OPCODE, "boot.img", Mode::Octet, Vec::new()).unwrap();
+        assert_eq!(
+            wrq.as_request(),
+            Some((WRITE_OPCODE, "boot.img", &Mode::Octet))
+        );
+
+        assert!(matches!(
+            Packet::new_request(ERROR_OPCODE, "boot.img", Mode::Octet, Vec::new()),
+            Err(Error::InvalidOpcode(ERROR_OPCODE))
+        ));
+    }
+
+    #[test]
+    fn test_is_error() {
+        let rrq = Packet::new_request(READ_OPCODE, "name.txt", Mode::Octet, Vec::new()).unwrap();
+        assert!(!rrq.is_error());
+        assert!(!Packet::new_data(1, vec![0; 4], 4).is_error());
+        assert!(!Packet::new_ack(1).is_error());
+        assert!(!Packet::OAck {
+            options: Vec::new()
+        }
+        .is_error());
+        assert!(Packet::new_error(FILE_NOT_FOUND, "File not found").is_error());
+    }
+
+    #[test]
+    fn test_error_accessors() {
+        let packet = Packet::new_error(FILE_NOT_FOUND, "File not found");
+        assert_eq!(packet.error_code(), Some(FILE_NOT_FOUND));
+        assert_eq!(packet.error_msg(), Some("File not found"));
+
+        let packet = Packet::new_ack(1);
+        assert_eq!(packet.error_code(), None);
+        assert_eq!(packet.error_msg(), None);
+    }
 }
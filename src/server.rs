@@ -0,0 +1,6979 @@
+use std::borrow::Cow;
+use std::collections::hash_map::{Entry, RandomState};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SendError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use ipnet::IpNet;
+
+use crate::metrics::{self, Metrics};
+use crate::netascii::NetAsciiReader;
+use crate::packet::{
+    Mode, Packet, ACCESS_VIOLATION, DEFAULT_BLKSIZE, DISK_FULL, ERROR_OPCODE, FILE_NOT_FOUND,
+    ILLEGAL_OP, MAX_BLKSIZE, MIN_BLKSIZE, OPTION_NEGOTIATION, READ_OPCODE, SEE_MSG, UNKNOWN_TID,
+    WRITE_OPCODE,
+};
+
+/// How often `run` wakes up from `recv_from` to check for a shutdown request
+pub(crate) const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a DATA block goes unacknowledged before it's sent again
+pub(crate) const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Times a DATA block is resent before the client is given up on
+pub(crate) const MAX_RETRANSMITS: u32 = 5;
+
+/// Bytes buffered between a transfer and its file unless `io_buffer_size`
+/// says otherwise
+pub(crate) const DEFAULT_IO_BUFFER: usize = 8 * 1024;
+
+/// Longest request accepted unless `max_request_size` says otherwise, as
+/// RFC 2347 keeps requests with options within 512 bytes
+pub(crate) const MAX_REQUEST_SIZE: usize = 512;
+
+/// Options of RFC 2347's extensions (RFCs 2348, 2349 and 7440), the only ones
+/// a request may carry under `strict`
+const STANDARD_OPTIONS: [&str; 4] = ["blksize", "timeout", "tsize", "windowsize"];
+
+/// Filename that reads `OK` when `enable_health` is set
+pub const HEALTH_FILE: &str = ".health";
+
+/// Filename that reads the listing of `root` when `enable_index` is set
+pub const INDEX_FILE: &str = ".index";
+
+/// Filename a WRQ is logged to rather than written under when
+/// `enable_log_sink` is set, unless `log_sink_name` says otherwise
+pub const LOG_SINK_FILE: &str = ".log";
+
+pub struct Config {
+    /// Directory files are served from and written to. Requested filenames
+    /// are resolved under it and may not climb out of it.
+    pub root: PathBuf,
+    /// Roots to serve clients in these subnets from instead of `root`. The
+    /// first subnet holding the client's address wins.
+    pub subnet_roots: Vec<(IpNet, PathBuf)>,
+    /// Directories under the root to serve requests in these modes from, to
+    /// keep text files apart from binary images. Other modes use the root.
+    pub mode_subdirs: Vec<(Mode, PathBuf)>,
+    /// Extensions that are never served or accepted, e.g. "sh" or "*.sh".
+    /// Compared case-insensitively against the final extension of the file.
+    pub denied_extensions: Vec<String>,
+    /// Longest filename, in bytes, a request may carry
+    pub max_filename_len: usize,
+    /// Block size offered to clients that negotiate options without asking
+    /// for one. Clients that don't negotiate always get 512-byte blocks.
+    pub blksize: usize,
+    /// Per-file block sizes as (glob, blksize) pairs, e.g. ("*.img", 1432).
+    /// The first pattern matching the filename takes precedence over
+    /// `blksize`, but not over a size the client asks for.
+    pub blksize_overrides: Vec<(String, usize)>,
+    /// Keep a transfer going when its client's packets start arriving from
+    /// a new port, as when a NAT drops and recreates its mapping. Either way
+    /// the move is logged; without this the new port gets UNKNOWN_TID.
+    pub tolerate_nat_rebind: bool,
+    /// Bytes available to a file written to the given path, if that can be
+    /// determined. A WRQ declaring a larger `tsize` is refused up front.
+    pub free_space: fn(&Path) -> Option<u64>,
+    /// How to answer an RRQ for a file that doesn't exist
+    pub not_found_behavior: NotFoundBehavior,
+    /// `chroot` into `root` when the server starts running, as a second line
+    /// of defence behind path resolution. This needs privileges, affects the
+    /// whole process, and is an error on platforms without `chroot`.
+    pub chroot: bool,
+    /// Sent back in the OACK to a client asking with a `serverinfo` option,
+    /// so the field can tell which server it reached. `None` ignores the
+    /// option like any other we don't understand.
+    pub banner: Option<String>,
+    /// Answer an RRQ for `.index` with a listing of the files in `root`, one
+    /// per line, leaving out any a client couldn't read. A client asking with
+    /// `listfmt=long` gets each name preceded by its size and mtime.
+    pub enable_index: bool,
+    /// Answer an RRQ for `.health` with `OK`, without touching the
+    /// filesystem, so a liveness probe can check the server end to end
+    pub enable_health: bool,
+    /// Log each line of a WRQ for `log_sink_name` to stderr, prefixed with
+    /// the client's address, instead of storing it. Meant for clients that
+    /// upload crash dumps or diagnostics for someone to look at.
+    pub enable_log_sink: bool,
+    /// Filename that reaches the log when `enable_log_sink` is set
+    pub log_sink_name: String,
+    /// Where the log sink's lines are written, in place of stderr
+    pub log_sink_writer: Option<Arc<Mutex<dyn Write + Send>>>,
+    /// Create the directories a WRQ's path needs under `root`. Without this
+    /// a write into a missing directory gets FILE_NOT_FOUND.
+    pub create_dirs: bool,
+    /// Give a WRQ's file the mtime (seconds since the epoch) and executable
+    /// bits (from an octal `mode`, on Unix) a client asks for with `mtime`
+    /// and `mode` options. Without this the options are ignored and uploads
+    /// get the current time and default permissions.
+    pub preserve_metadata: bool,
+    /// Most DATA blocks a single WRQ may write before it's aborted, as a
+    /// backstop against a client streaming full blocks forever
+    pub max_blocks: Option<u64>,
+    /// Bytes buffered between a transfer and its file, so small blocks are
+    /// coalesced into fewer reads and writes. A WRQ's data only has to reach
+    /// the file before its final ACK; an RRQ sends what it buffered even if
+    /// the file is truncated meanwhile.
+    pub io_buffer_size: usize,
+    /// Block buffers kept from finished RRQs for later ones to reuse, so a
+    /// busy server isn't allocating a pair for every transfer. 0 turns the
+    /// pool off.
+    pub buffer_pool_size: usize,
+    /// How to answer an RRQ for a file a WRQ is still writing. A WRQ for a
+    /// file with any transfer in progress is always refused.
+    pub busy_behavior: BusyBehavior,
+    /// Network interface, e.g. "eth1", to pin the socket to when the server
+    /// starts running, so a multi-homed host only serves one network. Only
+    /// Linux supports this; elsewhere it's ignored with a warning.
+    pub interface: Option<String>,
+    /// Longest a transfer may take from start to finish, however steadily its
+    /// client keeps it alive, before it's aborted with an ERROR
+    pub max_transfer_duration: Option<Duration>,
+    /// Serve every RRQ as octet, passing files through byte for byte even to
+    /// a client asking for netascii. Mail mode is still refused.
+    pub force_octet: bool,
+    /// Refuse every WRQ with ACCESS_VIOLATION
+    pub read_only: bool,
+    /// What clients in these subnets may do, refusing them anything else
+    /// with ACCESS_VIOLATION. The first subnet holding the client's address
+    /// wins; clients in none may read and write.
+    pub peer_capabilities: Vec<(IpNet, Capability)>,
+    /// Most transfers in progress at once. Requests past it are refused
+    /// until one finishes.
+    pub max_connections: Option<usize>,
+    /// Longest RRQ or WRQ accepted, in bytes. Anything longer is refused
+    /// with ILLEGAL_OP before its filename and options are parsed.
+    pub max_request_size: usize,
+    /// Keep to the RFCs (1350, 2347-2349 and 7440): ERROR codes go out and
+    /// are read big-endian, and a request with any other option is refused
+    /// with OPTION_NEGOTIATION, which rules out the option-driven extensions.
+    /// Those enabled in the config, like `enable_index`, still apply.
+    pub strict: bool,
+    /// Held before every packet a transfer sends, to see how clients cope
+    /// with a slow server. Only meant for testing.
+    pub artificial_delay: Duration,
+    /// Sent a `ProgressEvent` after every block a transfer moves. Events for
+    /// a receiver that's gone are dropped.
+    pub progress_tx: Option<Sender<ProgressEvent>>,
+    /// Told about transfers starting and ending and the bytes they move
+    pub metrics: Option<Arc<dyn Metrics>>,
+    /// Written a line for every request that's accepted, once it has passed
+    /// the checks and just before its transfer starts: when, from whom,
+    /// which way, the file and mode, and the options as negotiated so far
+    pub request_log: Option<Arc<Mutex<dyn Write + Send>>>,
+    /// Serve `name.gz` for an RRQ of a missing `name`, decompressed unless
+    /// the client negotiates `content-encoding=gzip`
+    #[cfg(feature = "gzip")]
+    pub gzip: bool,
+    /// Serve files from a memory map rather than with a `read` per block.
+    /// A file truncated by something other than this server while it's
+    /// being served kills the process with SIGBUS, so only use this where
+    /// served files are replaced by renaming over them, never rewritten.
+    #[cfg(feature = "mmap")]
+    pub mmap: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from("."),
+            subnet_roots: Vec::new(),
+            mode_subdirs: Vec::new(),
+            denied_extensions: Vec::new(),
+            max_filename_len: 255,
+            blksize: DEFAULT_BLKSIZE,
+            blksize_overrides: Vec::new(),
+            tolerate_nat_rebind: false,
+            free_space,
+            not_found_behavior: NotFoundBehavior::Error,
+            chroot: false,
+            banner: Some(format!("tftp {}", env!("CARGO_PKG_VERSION"))),
+            enable_index: false,
+            enable_health: false,
+            enable_log_sink: false,
+            log_sink_name: LOG_SINK_FILE.to_owned(),
+            log_sink_writer: None,
+            create_dirs: false,
+            preserve_metadata: false,
+            max_blocks: None,
+            io_buffer_size: DEFAULT_IO_BUFFER,
+            buffer_pool_size: 32,
+            busy_behavior: BusyBehavior::Reject,
+            interface: None,
+            max_transfer_duration: None,
+            force_octet: false,
+            read_only: false,
+            peer_capabilities: Vec::new(),
+            max_connections: None,
+            max_request_size: MAX_REQUEST_SIZE,
+            strict: false,
+            artificial_delay: Duration::ZERO,
+            progress_tx: None,
+            metrics: None,
+            request_log: None,
+            #[cfg(feature = "gzip")]
+            gzip: false,
+            #[cfg(feature = "mmap")]
+            mmap: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotFoundBehavior {
+    /// Reply with FILE_NOT_FOUND
+    Error,
+    /// Ignore the request, so scanning can't tell which files exist
+    Drop,
+}
+
+/// Which requests a client may make, as given in `peer_capabilities`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// RRQs only
+    ReadOnly,
+    /// WRQs only
+    WriteOnly,
+    /// Both, e.g. for a host in a subnet that's otherwise limited
+    ReadWrite,
+}
+
+/// What happens to an RRQ for a file that's being written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusyBehavior {
+    /// Refuse it with an ERROR
+    Reject,
+    /// Hold it until the write has finished, then serve the new contents
+    Wait,
+}
+
+/// Every transfer is served from the listening socket, so the server's
+/// transfer ID is always its own port. Tests that need to know it up front can
+/// bind the socket themselves and hand it over with [`Builder::socket`].
+/// A firewall in front of the server likewise only needs that one port open;
+/// there's no range of ephemeral ports to allow.
+pub struct Server {
+    socket: Arc<UdpSocket>,
+    config: Arc<Config>,
+    shutdown: Arc<AtomicBool>,
+    /// Set while `run` is serving
+    running: AtomicBool,
+    registry: Registry,
+    /// Named contents served from memory instead of from `root`
+    blobs: Mutex<HashMap<String, Arc<[u8]>>>,
+    locks: FileLocks,
+    pool: Arc<BufferPool>,
+    open_read: Option<Arc<OpenRead>>,
+    open_write: Option<Arc<OpenWrite>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// RRQ, the server is sending the file
+    Read,
+    /// WRQ, the server is receiving the file
+    Write,
+}
+
+/// A snapshot of a transfer in progress
+#[derive(Debug, Clone)]
+pub struct TransferInfo {
+    /// Unique to the transfer for the life of the server, and given in its
+    /// log lines and progress events
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub file: String,
+    pub direction: Direction,
+    /// Bytes sent or received so far
+    pub bytes: u64,
+    /// Bytes the whole transfer will take, when known: the file's size on a
+    /// read in octet mode, or the `tsize` a write declared
+    pub total: Option<u64>,
+}
+
+/// How far a transfer has got, as sent to `Config::progress_tx`
+#[derive(Debug, Clone)]
+pub struct ProgressEvent {
+    /// As in `TransferInfo::id`
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub file: String,
+    pub bytes_so_far: u64,
+    /// As in `TransferInfo::total`
+    pub total: Option<u64>,
+}
+
+/// The transfers currently in progress, shared between `run` and the workers.
+/// Entries are keyed by an id rather than the peer so a stale worker can't
+/// remove the entry of a newer transfer from the same address.
+#[derive(Clone, Default)]
+struct Registry {
+    transfers: Arc<Mutex<(u64, BTreeMap<u64, TransferInfo>)>>,
+    metrics: Option<Arc<dyn Metrics>>,
+}
+
+impl Registry {
+    /// An id for a transfer being accepted, counting up from 0
+    fn next_id(&self) -> u64 {
+        let next_id = &mut self.transfers.lock().unwrap().0;
+        let id = *next_id;
+        *next_id += 1;
+
+        id
+    }
+
+    /// Adds the transfer under its `id`, which came from `next_id`
+    fn register(
+        &self,
+        info: TransferInfo,
+        progress: Option<Sender<ProgressEvent>>,
+    ) -> RegistryEntry {
+        let mut guard = self.transfers.lock().unwrap();
+        let transfers = &mut guard.1;
+
+        let id = info.id;
+        transfers.insert(id, info);
+
+        if let Some(metrics) = &self.metrics {
+            metrics.increment(metrics::TRANSFERS_STARTED, 1);
+            metrics.observe(metrics::ACTIVE_TRANSFERS, transfers.len() as u64);
+        }
+
+        RegistryEntry {
+            registry: self.clone(),
+            id,
+            progress,
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    fn list(&self) -> Vec<TransferInfo> {
+        self.transfers.lock().unwrap().1.values().cloned().collect()
+    }
+}
+
+/// A worker's entry in the registry. It's removed when dropped, so every way
+/// out of a worker cleans it up.
+struct RegistryEntry {
+    registry: Registry,
+    id: u64,
+    progress: Option<Sender<ProgressEvent>>,
+    // Set once the transfer has been counted, so it's counted only once
+    finished: AtomicBool,
+}
+
+impl RegistryEntry {
+    fn add_bytes(&self, n: usize) {
+        if let Some(info) = self.registry.transfers.lock().unwrap().1.get_mut(&self.id) {
+            info.bytes += n as u64;
+
+            if let Some(metrics) = &self.registry.metrics {
+                let name = match info.direction {
+                    Direction::Read => metrics::BYTES_SENT,
+                    Direction::Write => metrics::BYTES_RECEIVED,
+                };
+                metrics.increment(name, n as u64);
+            }
+
+            if let Some(tx) = &self.progress {
+                let _ = tx.send(ProgressEvent {
+                    id: info.id,
+                    peer: info.peer,
+                    file: info.file.clone(),
+                    bytes_so_far: info.bytes,
+                    total: info.total,
+                });
+            }
+        }
+    }
+
+    fn set_total(&self, total: u64) {
+        if let Some(info) = self.registry.transfers.lock().unwrap().1.get_mut(&self.id) {
+            info.total = Some(total);
+        }
+    }
+
+    /// Counts the transfer as completed or failed, going by how it ended.
+    /// Only the first call counts.
+    fn finish(&self, res: &TransferResult) {
+        if self.finished.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        if let Some(metrics) = &self.registry.metrics {
+            let name = match res {
+                TransferResult::Completed => metrics::TRANSFERS_COMPLETED,
+                _ => metrics::TRANSFERS_FAILED,
+            };
+            metrics.increment(name, 1);
+        }
+    }
+
+    /// Takes the transfer out of the registry ahead of the worker ending
+    fn remove(&self) {
+        let mut guard = self.registry.transfers.lock().unwrap();
+        let transfers = &mut guard.1;
+        if transfers.remove(&self.id).is_none() {
+            return;
+        }
+
+        if let Some(metrics) = &self.registry.metrics {
+            metrics.observe(metrics::ACTIVE_TRANSFERS, transfers.len() as u64);
+        }
+    }
+}
+
+impl Drop for RegistryEntry {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+enum LockState {
+    Reading(usize),
+    Writing,
+}
+
+/// Buffers handed out to transfers and taken back when they're done with
+/// them, keeping up to `max` for the next to reuse
+#[derive(Default)]
+struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max: usize,
+    /// Buffers that had to be allocated, the pool having none to hand out
+    allocated: AtomicUsize,
+}
+
+impl BufferPool {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            ..Self::default()
+        }
+    }
+
+    /// A zeroed buffer of `len` bytes, back in the pool once it's dropped
+    fn take(&self, len: usize) -> PooledBuf<'_> {
+        let mut buf = self.buffers.lock().unwrap().pop().unwrap_or_else(|| {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            Vec::with_capacity(len)
+        });
+        buf.clear();
+        buf.resize(len, 0);
+
+        PooledBuf { buf, pool: self }
+    }
+}
+
+struct PooledBuf<'a> {
+    buf: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuf<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf<'_> {
+    fn drop(&mut self) {
+        let mut buffers = self.pool.buffers.lock().unwrap();
+        if buffers.len() < self.pool.max {
+            buffers.push(mem::take(&mut self.buf));
+        }
+    }
+}
+
+/// Files with transfers in progress, so none is read while it's half written.
+/// Any number of reads may share a file, but a write has it to itself.
+#[derive(Clone, Default)]
+struct FileLocks(Arc<(Mutex<HashMap<PathBuf, LockState>>, Condvar)>);
+
+impl FileLocks {
+    fn try_read(&self, path: &Path) -> Option<FileLock> {
+        let mut files = self.0 .0.lock().unwrap();
+        Self::lock_read(&mut files, path).then(|| self.held(path))
+    }
+
+    /// Like `try_read`, but waits for a write in progress to finish
+    fn read(&self, path: &Path) -> FileLock {
+        let mut files = self.0 .0.lock().unwrap();
+        while !Self::lock_read(&mut files, path) {
+            files = self.0 .1.wait(files).unwrap();
+        }
+
+        self.held(path)
+    }
+
+    fn try_write(&self, path: &Path) -> Option<FileLock> {
+        let mut files = self.0 .0.lock().unwrap();
+        if files.contains_key(path) {
+            return None;
+        }
+        files.insert(path.to_path_buf(), LockState::Writing);
+
+        Some(self.held(path))
+    }
+
+    fn lock_read(files: &mut HashMap<PathBuf, LockState>, path: &Path) -> bool {
+        match files.get_mut(path) {
+            Some(LockState::Writing) => false,
+            Some(LockState::Reading(readers)) => {
+                *readers += 1;
+                true
+            }
+            None => {
+                files.insert(path.to_path_buf(), LockState::Reading(1));
+                true
+            }
+        }
+    }
+
+    fn held(&self, path: &Path) -> FileLock {
+        FileLock {
+            locks: self.clone(),
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+/// A transfer's hold on its file, released when dropped
+struct FileLock {
+    locks: FileLocks,
+    path: PathBuf,
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let (files, released) = &*self.locks.0;
+        let mut files = files.lock().unwrap();
+
+        if let Some(LockState::Reading(readers)) = files.get_mut(&self.path) {
+            if *readers > 1 {
+                *readers -= 1;
+                return;
+            }
+        }
+        files.remove(&self.path);
+        released.notify_all();
+    }
+}
+
+/// Stops a running `Server` from another thread
+#[derive(Clone)]
+pub struct ShutdownHandle(pub(crate) Arc<AtomicBool>);
+
+impl ShutdownHandle {
+    /// New requests are refused from now on, and `run` returns once the
+    /// transfers already in progress have finished.
+    pub fn shutdown(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Sets up a `Server`, checking its configuration makes sense before any
+/// socket is bound. Start one with `Server::builder()`.
+pub struct Builder {
+    config: Config,
+    listen: Option<Listen>,
+    open_read: Option<Arc<OpenRead>>,
+    open_write: Option<Arc<OpenWrite>>,
+}
+
+/// A source an RRQ can be served from, as opened by a `Builder::open_read_fn`
+/// hook
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// Opens the file an RRQ asks for, by its name as requested
+pub type OpenRead = dyn Fn(&str) -> io::Result<Box<dyn ReadSeek>> + Send + Sync;
+
+/// Opens where a WRQ's file goes, by its name as requested
+pub type OpenWrite = dyn Fn(&str) -> io::Result<Box<dyn Write + Send>> + Send + Sync;
+
+enum Listen {
+    Addrs(io::Result<Vec<SocketAddr>>),
+    Socket(UdpSocket),
+}
+
+impl Builder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn bind<A: ToSocketAddrs>(mut self, addr: A) -> Self {
+        let addrs = addr.to_socket_addrs().map(|addrs| addrs.collect());
+        self.listen = Some(Listen::Addrs(addrs));
+        self
+    }
+
+    /// Serve on an already bound socket, as with `Server::from_socket`
+    pub fn socket(mut self, socket: UdpSocket) -> Self {
+        self.listen = Some(Listen::Socket(socket));
+        self
+    }
+
+    /// Serve RRQs from whatever `open` returns for the requested name rather
+    /// than from `root`, e.g. to serve out of a database or generate files.
+    /// Requests are still checked against the config, but nothing is looked
+    /// up on disk, and an error from `open` is sent to the client.
+    pub fn open_read_fn<F>(mut self, open: F) -> Self
+    where
+        F: Fn(&str) -> io::Result<Box<dyn ReadSeek>> + Send + Sync + 'static,
+    {
+        self.open_read = Some(Arc::new(open));
+        self
+    }
+
+    /// Like `open_read_fn`, for where WRQs are written. What's written is
+    /// flushed before the final ACK.
+    pub fn open_write_fn<F>(mut self, open: F) -> Self
+    where
+        F: Fn(&str) -> io::Result<Box<dyn Write + Send>> + Send + Sync + 'static,
+    {
+        self.open_write = Some(Arc::new(open));
+        self
+    }
+
+    pub fn build(self) -> Result<Server, ConfigError> {
+        validate(&self.config)?;
+
+        let socket = match self.listen {
+            Some(Listen::Addrs(addrs)) => UdpSocket::bind(&addrs?[..])?,
+            Some(Listen::Socket(socket)) => socket,
+            None => return Err(ConfigError::NoAddress),
+        };
+
+        let mut server = Server::from_socket(socket, self.config)?;
+        server.open_read = self.open_read;
+        server.open_write = self.open_write;
+
+        Ok(server)
+    }
+}
+
+/// Rejects settings that contradict each other or can't be served
+fn validate(config: &Config) -> Result<(), ConfigError> {
+    let blksizes = std::iter::once(config.blksize)
+        .chain(config.blksize_overrides.iter().map(|(_, blksize)| *blksize));
+    for blksize in blksizes {
+        if !(MIN_BLKSIZE..=MAX_BLKSIZE).contains(&blksize) {
+            return Err(ConfigError::BlksizeOutOfRange(blksize));
+        }
+    }
+
+    if config.max_filename_len == 0 {
+        return Err(ConfigError::ZeroFilenameLen);
+    }
+    if config.max_blocks == Some(0) {
+        return Err(ConfigError::ZeroMaxBlocks);
+    }
+    if !config.root.is_dir() {
+        return Err(ConfigError::RootNotDirectory(config.root.clone()));
+    }
+    for (_, root) in &config.subnet_roots {
+        if !root.is_dir() {
+            return Err(ConfigError::RootNotDirectory(root.clone()));
+        }
+    }
+    for (_, subdir) in &config.mode_subdirs {
+        // Only a plain path down from the root stays inside it
+        if !subdir
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(ConfigError::BadModeSubdir(subdir.clone()));
+        }
+        if !config.root.join(subdir).is_dir() {
+            return Err(ConfigError::RootNotDirectory(config.root.join(subdir)));
+        }
+    }
+    // Only `root` is left to see once the process is confined to it
+    if config.chroot && !config.subnet_roots.is_empty() {
+        return Err(ConfigError::SubnetRootsWithChroot);
+    }
+    if config.chroot && cfg!(not(unix)) {
+        return Err(ConfigError::ChrootUnsupported);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `blksize` or one of the overrides is outside what RFC 2348 allows
+    BlksizeOutOfRange(usize),
+    /// `max_filename_len` is 0, so every request would be refused
+    ZeroFilenameLen,
+    /// `max_blocks` is 0, so every write would be aborted
+    ZeroMaxBlocks,
+    RootNotDirectory(PathBuf),
+    ChrootUnsupported,
+    /// `subnet_roots` lie outside the `chroot`
+    SubnetRootsWithChroot,
+    /// A `mode_subdirs` entry is absolute or climbs out of the root
+    BadModeSubdir(PathBuf),
+    /// Neither an address to bind nor a socket was given
+    NoAddress,
+    Io(io::Error),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::BlksizeOutOfRange(blksize) => write!(
+                f,
+                "block size {} is outside {}..={}",
+                blksize, MIN_BLKSIZE, MAX_BLKSIZE
+            ),
+            ConfigError::ZeroFilenameLen => {
+                write!(f, "max_filename_len of 0 refuses every request")
+            }
+            ConfigError::ZeroMaxBlocks => write!(f, "max_blocks of 0 aborts every write"),
+            ConfigError::RootNotDirectory(root) => {
+                write!(f, "root {} is not a directory", root.display())
+            }
+            ConfigError::ChrootUnsupported => write!(f, "chroot is only supported on Unix"),
+            ConfigError::SubnetRootsWithChroot => {
+                write!(f, "subnet_roots can't be reached from inside a chroot")
+            }
+            ConfigError::BadModeSubdir(subdir) => {
+                write!(
+                    f,
+                    "mode subdirectory {} isn't under the root",
+                    subdir.display()
+                )
+            }
+            ConfigError::NoAddress => write!(f, "no address or socket to serve on"),
+            ConfigError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+/// Outgoing side of a transfer. Workers receive their packets over a channel
+/// from `run`, so sending is all they need from the network; abstracting it
+/// lets tests drive a worker without real sockets.
+pub trait Transport: Send + Sync {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+}
+
+/// The server's socket as the serve loop and workers use it. Under
+/// `strict`, ERRORs cross the wire with their code big-endian, as RFC 1350
+/// has it, and are turned around on the way in and out so the rest of the
+/// server sees them as `Packet` reads and writes them.
+struct WireSocket {
+    inner: Arc<UdpSocket>,
+    strict: bool,
+}
+
+impl WireSocket {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (len, addr) = self.inner.recv_from(buf)?;
+        if self.strict {
+            swap_error_code(&mut buf[..len]);
+        }
+
+        Ok((len, addr))
+    }
+}
+
+impl Transport for WireSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        // Only an ERROR is turned around, so only an ERROR is copied
+        if !self.strict || !buf.starts_with(&ERROR_OPCODE.to_be_bytes()) {
+            return self.inner.send_to(buf, addr);
+        }
+
+        let mut buf = buf.to_vec();
+        swap_error_code(&mut buf);
+        self.inner.send_to(&buf, addr)
+    }
+}
+
+/// Reverses the byte order of an ERROR's code, leaving any other packet be
+fn swap_error_code(packet: &mut [u8]) {
+    if packet.len() >= 4 && packet[..2] == ERROR_OPCODE.to_be_bytes() {
+        packet.swap(2, 3);
+    }
+}
+
+/// Sends to wherever the peer currently is rather than where the worker
+/// thinks it is, so a transfer can follow a client whose NAT rebinds it
+struct PeerSocket<T> {
+    inner: Arc<T>,
+    peer: Arc<Mutex<SocketAddr>>,
+    /// `Config::artificial_delay`
+    delay: Duration,
+}
+
+impl<T: Transport> Transport for PeerSocket<T> {
+    fn send_to(&self, buf: &[u8], _addr: SocketAddr) -> io::Result<usize> {
+        if !self.delay.is_zero() {
+            thread::sleep(self.delay);
+        }
+        let peer = *self.peer.lock().unwrap();
+        self.inner.send_to(buf, peer)
+    }
+}
+
+/// What a transfer's worker is handed, whatever it serves from or writes
+/// to: where the peer is, the packets the serve loop passes on from it, the
+/// options it asked for, and what the transfer is held to and counted in
+struct Transfer<T> {
+    socket: Arc<T>,
+    dst: SocketAddr,
+    rx: Receiver<Packet>,
+    options: Vec<(String, String)>,
+    /// When `Config::max_transfer_duration` runs out
+    deadline: Option<Instant>,
+    pool: Arc<BufferPool>,
+    entry: RegistryEntry,
+}
+
+/// Clears its flag when dropped, however the scope it's in is left
+struct ClearOnDrop<'a>(&'a AtomicBool);
+
+impl Drop for ClearOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// How a transfer worker finished
+#[derive(Debug)]
+enum TransferResult {
+    /// Every block was sent and ACKed, or received, written and ACKed
+    Completed,
+    /// The client ended it with an ERROR
+    Aborted { code: u16, msg: String },
+    /// The client stopped ACKing, even after retransmits
+    TimedOut,
+    /// The client went away without a word: its host refused our packets,
+    /// or the serve loop dropped the connection
+    PeerGone,
+    /// We ended it with an ERROR, e.g. for an unsupported mode or a client
+    /// sending too much
+    Rejected { code: u16, msg: String },
+    /// A local I/O error ended it, the client having been told if possible
+    Failed(io::Error),
+}
+
+/// A transfer worker and the channel used to hand it packets from its peer
+struct Connection {
+    tx: Sender<Packet>,
+    handle: JoinHandle<()>,
+    peer: Arc<Mutex<SocketAddr>>,
+    /// The request that started it, as it was sent
+    request: Vec<u8>,
+    /// As in `TransferInfo::id`
+    id: u64,
+}
+
+/// The transfers in progress, keyed by peer. A count of transfers per peer
+/// IP is kept alongside, so questions about a host don't need a scan.
+struct ConnectionTable<C = Connection> {
+    connections: HashMap<SocketAddr, C>,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl<C> Default for ConnectionTable<C> {
+    fn default() -> Self {
+        Self {
+            connections: HashMap::new(),
+            per_ip: HashMap::new(),
+        }
+    }
+}
+
+impl<C> ConnectionTable<C> {
+    fn insert(&mut self, peer: SocketAddr, conn: C) -> Option<C> {
+        let old = self.connections.insert(peer, conn);
+        if old.is_none() {
+            *self.per_ip.entry(peer.ip()).or_default() += 1;
+        }
+
+        old
+    }
+
+    fn remove(&mut self, peer: &SocketAddr) -> Option<C> {
+        let conn = self.connections.remove(peer)?;
+        forget_ip(&mut self.per_ip, peer.ip());
+
+        Some(conn)
+    }
+
+    fn get(&self, peer: &SocketAddr) -> Option<&C> {
+        self.connections.get(peer)
+    }
+
+    fn count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Transfers with a peer at `ip`, on any port
+    fn count_for_peer(&self, ip: IpAddr) -> usize {
+        self.per_ip.get(&ip).copied().unwrap_or(0)
+    }
+
+    /// Drops every connection `keep` returns false for
+    fn retain(&mut self, mut keep: impl FnMut(&C) -> bool) {
+        let per_ip = &mut self.per_ip;
+        self.connections.retain(|peer, conn| {
+            let kept = keep(conn);
+            if !kept {
+                forget_ip(per_ip, peer.ip());
+            }
+
+            kept
+        });
+    }
+
+    /// Finds the transfer a packet from an unknown port most likely belongs
+    /// to: the only one with a peer at the same IP. With several there's no
+    /// telling which, if any, moved.
+    fn find_rebind(&self, addr: SocketAddr) -> Option<SocketAddr> {
+        if self.count_for_peer(addr.ip()) != 1 {
+            return None;
+        }
+
+        self.connections
+            .keys()
+            .find(|peer| peer.ip() == addr.ip())
+            .copied()
+    }
+}
+
+fn forget_ip(per_ip: &mut HashMap<IpAddr, usize>, ip: IpAddr) {
+    if let Entry::Occupied(mut count) = per_ip.entry(ip) {
+        *count.get_mut() -= 1;
+        if *count.get() == 0 {
+            count.remove();
+        }
+    }
+}
+
+impl Server {
+    pub fn builder() -> Builder {
+        Builder {
+            config: Config::default(),
+            listen: None,
+            open_read: None,
+            open_write: None,
+        }
+    }
+
+    pub fn bind<A: ToSocketAddrs>(addr: A, config: Config) -> io::Result<Self> {
+        Self::from_socket(UdpSocket::bind(addr)?, config)
+    }
+
+    /// Serves on a socket that was bound elsewhere, e.g. one passed in by
+    /// systemd socket activation or bound before dropping privileges.
+    ///
+    /// The socket's read timeout is overwritten, as the serve loop relies on
+    /// it to notice a shutdown.
+    pub fn from_socket(socket: UdpSocket, config: Config) -> io::Result<Self> {
+        socket.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+
+        let registry = Registry {
+            metrics: config.metrics.clone(),
+            ..Registry::default()
+        };
+
+        Ok(Self {
+            socket: Arc::new(socket),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            running: AtomicBool::new(false),
+            registry,
+            blobs: Mutex::default(),
+            locks: FileLocks::default(),
+            pool: Arc::new(BufferPool::new(config.buffer_pool_size)),
+            open_read: None,
+            open_write: None,
+            config: Arc::new(config),
+        })
+    }
+
+    /// Serves `contents` to any RRQ for `name` from now on, in place of the
+    /// file of that name under the root, if there is one
+    pub fn serve_bytes(&self, name: &str, contents: Vec<u8>) {
+        self.blobs
+            .lock()
+            .unwrap()
+            .insert(name.trim_start_matches('/').to_owned(), contents.into());
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The transfers currently in progress, in the order they were accepted
+    pub fn active_transfers(&self) -> Vec<TransferInfo> {
+        self.registry.list()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
+    }
+
+    /// Whether `run` is serving, from when it's called until it returns, as
+    /// a cheap liveness check
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Triggers a graceful shutdown when SIGTERM or SIGINT is received.
+    ///
+    /// This is best-effort: transfers in progress are allowed to drain, but a
+    /// client that has gone away keeps its worker waiting, so an orchestrator
+    /// may still have to kill the process once its grace period runs out.
+    #[cfg(all(unix, feature = "signals"))]
+    pub fn handle_signals(&self) -> io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+
+        for signal in [SIGTERM, SIGINT] {
+            signal_hook::flag::register(signal, self.shutdown.clone())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn run(&self) -> io::Result<()> {
+        self.running.store(true, Ordering::Relaxed);
+        let _running = ClearOnDrop(&self.running);
+
+        if let Some(interface) = &self.config.interface {
+            bind_to_device(&*self.socket, interface)?;
+        }
+
+        let root = if self.config.chroot {
+            chroot(&self.config.root)?;
+            PathBuf::from("/")
+        } else {
+            self.config.root.clone()
+        };
+
+        let socket = &Arc::new(WireSocket {
+            inner: self.socket.clone(),
+            strict: self.config.strict,
+        });
+        let mut connections: ConnectionTable = ConnectionTable::default();
+
+        // Sized for the default block size and for a byte past the longest
+        // request, so one too long shows as such rather than cut short, and
+        // grown only once a transfer negotiates more
+        let mut buf = vec![0; request_buf_len(&self.config)];
+        loop {
+            connections.retain(|conn| !conn.handle.is_finished());
+
+            let shutting_down = self.shutdown.load(Ordering::Relaxed);
+            if shutting_down && connections.count() == 0 {
+                return Ok(());
+            }
+
+            let (len, addr) = match socket.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            if oversized_request(&self.config, &buf[..len]) {
+                socket.send_to(
+                    Packet::new_error(ILLEGAL_OP, "Request too long")
+                        .serialize()
+                        .as_slice(),
+                    addr,
+                )?;
+                continue;
+            }
+
+            let mut packet = match Packet::deserialize(&buf[..len]) {
+                Ok(p) => p,
+                Err(e) => {
+                    // Answered so a client that sent a bad request fails fast
+                    // rather than timing out
+                    eprintln!("Error: {}", e);
+                    socket.send_to(
+                        Packet::new_error(ILLEGAL_OP, e.to_string())
+                            .serialize()
+                            .as_slice(),
+                        addr,
+                    )?;
+
+                    continue;
+                }
+            };
+
+            // A peer repeating the request for the transfer it has going
+            // most likely didn't hear the reply, which its worker sends again.
+            // Once that worker's gone, it's asking afresh.
+            if let Some(conn) = connections.get(&addr) {
+                if conn.request == buf[..len] {
+                    match conn.tx.send(packet) {
+                        Ok(()) => continue,
+                        Err(SendError(request)) => {
+                            connections.remove(&addr);
+                            packet = request;
+                        }
+                    }
+                }
+            }
+
+            match packet {
+                // Create processes for these:
+                Packet::Request {
+                    op_code,
+                    file,
+                    mode,
+                    mut options,
+                } => {
+                    if shutting_down {
+                        socket.send_to(
+                            Packet::new_error(SEE_MSG, "Server is shutting down")
+                                .serialize()
+                                .as_slice(),
+                            addr,
+                        )?;
+                        continue;
+                    }
+                    if self
+                        .config
+                        .max_connections
+                        .is_some_and(|max| connections.count() >= max)
+                    {
+                        socket.send_to(
+                            Packet::new_error(SEE_MSG, "Too many transfers")
+                                .serialize()
+                                .as_slice(),
+                            addr,
+                        )?;
+                        continue;
+                    }
+                    // Picked by the mode asked for, even if it's served in
+                    // another
+                    let root = root_for(&self.config, &root, addr.ip(), &mode);
+                    let mode = transfer_mode(&self.config, mode);
+
+                    if let Err(err) = authorize(&self.config, op_code, &file) {
+                        socket.send_to(err.serialize().as_slice(), addr)?;
+                        continue;
+                    }
+                    if let Err(err) = check_peer(&self.config, addr.ip(), op_code) {
+                        socket.send_to(err.serialize().as_slice(), addr)?;
+                        continue;
+                    }
+                    if let Err(err) = check_strict(&self.config, &options) {
+                        socket.send_to(err.serialize().as_slice(), addr)?;
+                        continue;
+                    }
+
+                    let path = match resolve(&root, &file) {
+                        Some(path) => path,
+                        None => {
+                            socket.send_to(
+                                Packet::new_error(ACCESS_VIOLATION, "Access violation")
+                                    .serialize()
+                                    .as_slice(),
+                                addr,
+                            )?;
+                            continue;
+                        }
+                    };
+
+                    // Contents served from memory rather than a file
+                    let name = file.trim_start_matches('/');
+                    let blob = self.blobs.lock().unwrap().get(name).cloned();
+                    let log_sink = op_code == WRITE_OPCODE
+                        && self.config.enable_log_sink
+                        && name == self.config.log_sink_name;
+                    // Hooks that stand in for the filesystem, opening what's
+                    // asked for themselves
+                    let open_read = self.open_read.clone().filter(|_| op_code == READ_OPCODE);
+                    let open_write = self.open_write.clone().filter(|_| op_code == WRITE_OPCODE);
+                    let hooked = open_read.is_some() || open_write.is_some();
+                    let long = take_listfmt(&mut options);
+                    let memory = if op_code != READ_OPCODE {
+                        None
+                    } else if blob.is_some() {
+                        blob
+                    } else if self.config.enable_health && name == HEALTH_FILE {
+                        Some(Arc::from(&b"OK"[..]))
+                    } else {
+                        None
+                    };
+                    // The index is listed by its worker, then served from memory
+                    let index = op_code == READ_OPCODE
+                        && memory.is_none()
+                        && self.config.enable_index
+                        && name == INDEX_FILE;
+                    if index && long {
+                        options.push(("listfmt".to_owned(), "long".to_owned()));
+                    }
+
+                    let (path, gunzip) = if op_code == READ_OPCODE && !hooked {
+                        negotiate_gzip(&self.config, path, memory.is_some() || index, &mut options)
+                    } else {
+                        (path, false)
+                    };
+
+                    if op_code == READ_OPCODE
+                        && memory.is_none()
+                        && !index
+                        && !hooked
+                        && self.config.not_found_behavior == NotFoundBehavior::Drop
+                        && !path.exists()
+                    {
+                        eprintln!("Dropping request from {} for missing {}", addr, file);
+                        continue;
+                    }
+
+                    // The index's worker answers a probe once it has the
+                    // listing
+                    if op_code == READ_OPCODE && !hooked && !index {
+                        let size = match &memory {
+                            Some(contents) => Some(contents.len() as u64),
+                            None if gunzip => gunzipped_size(&path),
+                            None => file_size(&path),
+                        };
+                        if let Some(oack) = probe(&options, size) {
+                            socket.send_to(oack.serialize().as_slice(), addr)?;
+                            continue;
+                        }
+                    }
+
+                    negotiate_blksize(&self.config, &file, &mut options);
+                    negotiate_serverinfo(&self.config, &mut options);
+                    fit_blksize(&mut buf, &options);
+
+                    // Streaming into a FIFO leaves no file to set metadata on
+                    let writes_file =
+                        op_code == WRITE_OPCODE && !log_sink && !hooked && !is_fifo(&path);
+                    if let Err(err) = negotiate_metadata(&self.config, writes_file, &mut options) {
+                        socket.send_to(err.serialize().as_slice(), addr)?;
+                        continue;
+                    }
+
+                    if op_code == WRITE_OPCODE && !log_sink && !hooked {
+                        if let Err(err) = preflight_write(&self.config, &root, &path, &options) {
+                            socket.send_to(err.serialize().as_slice(), addr)?;
+                            continue;
+                        }
+                    }
+
+                    // Held by the worker until it's done with the file. A read
+                    // that has to wait for a write to finish is left without
+                    // one, and takes it in its worker instead.
+                    let lock = if memory.is_some() || index || log_sink || hooked {
+                        None
+                    } else if op_code == READ_OPCODE {
+                        match self.locks.try_read(&path) {
+                            Some(lock) => Some(lock),
+                            None if self.config.busy_behavior == BusyBehavior::Wait => None,
+                            None => {
+                                socket.send_to(
+                                    Packet::new_error(SEE_MSG, "File is being written")
+                                        .serialize()
+                                        .as_slice(),
+                                    addr,
+                                )?;
+                                continue;
+                            }
+                        }
+                    } else {
+                        match self.locks.try_write(&path) {
+                            Some(lock) => Some(lock),
+                            None => {
+                                socket.send_to(
+                                    Packet::new_error(SEE_MSG, "File is in use")
+                                        .serialize()
+                                        .as_slice(),
+                                    addr,
+                                )?;
+                                continue;
+                            }
+                        }
+                    };
+                    let locks = self.locks.clone();
+
+                    let id = self.registry.next_id();
+                    if let Some(log) = &self.config.request_log {
+                        let line = request_line(
+                            SystemTime::now(),
+                            id,
+                            addr,
+                            op_code,
+                            &file,
+                            &mode,
+                            &options,
+                        );
+                        if let Err(e) = writeln!(log.lock().unwrap(), "{}", line) {
+                            eprintln!("Error: {}", e);
+                        }
+                    }
+
+                    let (tx, rx) = mpsc::channel();
+
+                    let peer = Arc::new(Mutex::new(addr));
+                    let socket = Arc::new(PeerSocket {
+                        inner: socket.clone(),
+                        peer: peer.clone(),
+                        delay: self.config.artificial_delay,
+                    });
+
+                    let direction = if op_code == READ_OPCODE {
+                        Direction::Read
+                    } else {
+                        Direction::Write
+                    };
+                    let requested = file.clone();
+                    let entry = self.registry.register(
+                        TransferInfo {
+                            id,
+                            peer: addr,
+                            file,
+                            direction,
+                            bytes: 0,
+                            total: None,
+                        },
+                        self.config.progress_tx.clone(),
+                    );
+
+                    let max_blocks = self.config.max_blocks;
+                    let io_buffer = self.config.io_buffer_size;
+                    let transfer = Transfer {
+                        socket: socket.clone(),
+                        dst: addr,
+                        rx,
+                        options,
+                        deadline: self
+                            .config
+                            .max_transfer_duration
+                            .map(|max| Instant::now() + max),
+                        pool: self.pool.clone(),
+                        entry,
+                    };
+                    let handle = if let Some(contents) = memory {
+                        spawn_worker(socket, id, move || {
+                            report(&transfer, memory_process(&transfer, contents, mode));
+                        })
+                    } else if index {
+                        let config = self.config.clone();
+                        spawn_worker(socket, id, move || {
+                            let res = index_process(&transfer, &config, &root, long, mode);
+                            report(&transfer, res);
+                        })
+                    } else if log_sink {
+                        let out = self.config.log_sink_writer.clone();
+                        spawn_worker(socket, id, move || {
+                            report(&transfer, log_sink_process(&transfer, out, max_blocks));
+                        })
+                    } else if let Some(open) = open_read {
+                        spawn_worker(socket, id, move || {
+                            let res = hooked_read_process(&transfer, &*open, &requested, mode);
+                            report(&transfer, res);
+                        })
+                    } else if let Some(open) = open_write {
+                        spawn_worker(socket, id, move || {
+                            let res =
+                                hooked_write_process(&transfer, &*open, &requested, max_blocks);
+                            report(&transfer, res);
+                        })
+                    } else if op_code == READ_OPCODE {
+                        #[cfg(feature = "mmap")]
+                        let mmap = self.config.mmap;
+                        spawn_worker(socket, id, move || {
+                            let _lock = lock.unwrap_or_else(|| locks.read(&path));
+                            #[cfg(feature = "gzip")]
+                            if gunzip {
+                                let res = gunzip_process(&transfer, path, mode, io_buffer);
+                                return report(&transfer, res);
+                            }
+                            #[cfg(feature = "mmap")]
+                            if mmap {
+                                let res = mmap_process(&transfer, path, mode, io_buffer);
+                                return report(&transfer, res);
+                            }
+                            report(&transfer, read_process(&transfer, path, mode, io_buffer));
+                        })
+                    } else if op_code == WRITE_OPCODE {
+                        spawn_worker(socket, id, move || {
+                            let res = write_process(&transfer, path, io_buffer, max_blocks, lock);
+                            report(&transfer, res);
+                        })
+                    } else {
+                        panic!("Request op_code is neither 1 or 2");
+                    };
+
+                    let request = buf[..len].to_vec();
+                    connections.insert(
+                        addr,
+                        Connection {
+                            tx,
+                            handle,
+                            peer,
+                            request,
+                            id,
+                        },
+                    );
+                }
+
+                // Only a server sends these, so the peer is confused or
+                // malicious. The ERROR ends its transfer, if it has one.
+                Packet::OAck { .. } => {
+                    let err = Packet::new_error(ILLEGAL_OP, "Unexpected OACK");
+                    socket.send_to(err.serialize().as_slice(), addr)?;
+
+                    if let Some(conn) = connections.remove(&addr) {
+                        let _ = conn.tx.send(err);
+                    }
+                }
+
+                // Sent to processes: Data, Ack, Error
+                packet => {
+                    if connections.get(&addr).is_none() {
+                        if let Some(old) = connections.find_rebind(addr) {
+                            if self.config.tolerate_nat_rebind {
+                                let conn = connections.remove(&old).unwrap();
+                                let line = format!("Peer {} moved to {}, following it", old, addr);
+                                eprintln!("{}", tagged(conn.id, line));
+
+                                *conn.peer.lock().unwrap() = addr;
+                                connections.insert(addr, conn);
+                            } else {
+                                let id = connections.get(&old).unwrap().id;
+                                let line =
+                                    format!("Peer {} appears to have moved to {}", old, addr);
+                                eprintln!("{}", tagged(id, line));
+                            }
+                        }
+                    }
+
+                    if let Some(conn) = connections.get(&addr) {
+                        // The worker may have finished since the table was
+                        // last swept, so the packet is stale
+                        if let Err(SendError(packet)) = conn.tx.send(packet) {
+                            connections.remove(&addr);
+                            if !packet.is_error() {
+                                socket.send_to(
+                                    Packet::new_error(UNKNOWN_TID, "Transfer has ended")
+                                        .serialize()
+                                        .as_slice(),
+                                    addr,
+                                )?;
+                            }
+                        }
+                    } else if let Packet::Error { code, msg } = packet {
+                        // ERRORs are never answered (RFC 1350), or two
+                        // confused hosts could trade them forever
+                        eprintln!("Stray error {} from {}: {}", code, addr, msg);
+                    } else {
+                        // A host with another transfer going has most likely
+                        // got the port wrong; any other never started one
+                        let msg = if connections.count_for_peer(addr.ip()) > 0 {
+                            "Unknown transfer ID"
+                        } else {
+                            "No transfer in progress"
+                        };
+                        socket.send_to(
+                            Packet::new_error(UNKNOWN_TID, msg).serialize().as_slice(),
+                            addr,
+                        )?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How big the serve loop's receive buffer starts out
+pub(crate) fn request_buf_len(config: &Config) -> usize {
+    (DEFAULT_BLKSIZE + 4).max(config.max_request_size + 1)
+}
+
+/// Whether `packet` is an RRQ or WRQ longer than `max_request_size`
+pub(crate) fn oversized_request(config: &Config, packet: &[u8]) -> bool {
+    let op_code = match packet {
+        [a, b, ..] => u16::from_be_bytes([*a, *b]),
+        _ => return false,
+    };
+
+    matches!(op_code, READ_OPCODE | WRITE_OPCODE) && packet.len() > config.max_request_size
+}
+
+/// Refuses, under `strict`, a request with an option the RFCs don't define,
+/// which would otherwise be ignored or taken as asking for an extension
+pub(crate) fn check_strict(config: &Config, options: &[(String, String)]) -> Result<(), Packet> {
+    if !config.strict {
+        return Ok(());
+    }
+
+    match options
+        .iter()
+        .find(|(name, _)| !STANDARD_OPTIONS.contains(&name.as_str()))
+    {
+        Some((name, _)) => Err(Packet::new_error(
+            OPTION_NEGOTIATION,
+            format!("Unsupported option {}", name),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// The mode an RRQ is served in, which is octet for netascii under
+/// `force_octet`. Anything else is left for the worker to judge.
+pub(crate) fn transfer_mode(config: &Config, mode: Mode) -> Mode {
+    match mode {
+        Mode::NetAscii if config.force_octet => Mode::Octet,
+        mode => mode,
+    }
+}
+
+/// Checks a request against the config before any worker is spawned.
+/// The returned ERROR packet should be sent back to the client as is.
+pub(crate) fn authorize(config: &Config, op_code: u16, file: &str) -> Result<(), Packet> {
+    if config.read_only && op_code == WRITE_OPCODE {
+        return Err(Packet::new_error(ACCESS_VIOLATION, "Server is read-only"));
+    }
+
+    if file.len() > config.max_filename_len {
+        return Err(Packet::new_error(ILLEGAL_OP, "Filename too long"));
+    }
+
+    let ext = match Path::new(file).extension().and_then(|e| e.to_str()) {
+        Some(ext) => ext,
+        None => return Ok(()),
+    };
+
+    let denied = config.denied_extensions.iter().any(|denied| {
+        let denied = denied.trim_start_matches('*').trim_start_matches('.');
+        denied.eq_ignore_ascii_case(ext)
+    });
+
+    if denied {
+        return Err(Packet::new_error(ACCESS_VIOLATION, "Access violation"));
+    }
+
+    Ok(())
+}
+
+/// Refuses a request `peer` isn't capable of making under
+/// `peer_capabilities`
+pub(crate) fn check_peer(config: &Config, peer: IpAddr, op_code: u16) -> Result<(), Packet> {
+    // An IPv4 client of a dual-stack socket arrives as ::ffff:a.b.c.d
+    let peer = peer.to_canonical();
+    let capability = config
+        .peer_capabilities
+        .iter()
+        .find(|(subnet, _)| subnet.contains(&peer))
+        .map_or(Capability::ReadWrite, |(_, capability)| *capability);
+
+    match capability {
+        Capability::ReadOnly if op_code == WRITE_OPCODE => Err(Packet::new_error(
+            ACCESS_VIOLATION,
+            "Writes not allowed from this host",
+        )),
+        Capability::WriteOnly if op_code == READ_OPCODE => Err(Packet::new_error(
+            ACCESS_VIOLATION,
+            "Reads not allowed from this host",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Settles the block size of a transfer (RFC 2348), leaving it in the
+/// request's `blksize` option for the worker to use and echo in its OACK.
+///
+/// A size the client asks for wins, capped at what the RFC allows. One below
+/// the RFC's minimum of 8 is invalid and dropped, as a malformed one is. A
+/// client that negotiates other options is offered the size configured for
+/// the file, if it differs from the default, except under `strict`, where an
+/// OACK only answers options the client sent (RFC 2347). Clients that send no
+/// options at all may not understand an OACK, so they're left with 512-byte
+/// blocks.
+pub(crate) fn negotiate_blksize(config: &Config, file: &str, options: &mut Vec<(String, String)>) {
+    if options.is_empty() {
+        return;
+    }
+
+    let requested = options
+        .iter()
+        .find(|(name, _)| name == "blksize")
+        .map(|(_, value)| value.parse::<usize>());
+    options.retain(|(name, _)| name != "blksize");
+
+    let blksize = match requested {
+        Some(Ok(blksize)) if blksize >= MIN_BLKSIZE => blksize.min(MAX_BLKSIZE),
+        // Malformed or too small, so ignore it like any option we don't
+        // understand
+        Some(_) => return,
+        None if config.strict => return,
+        None => config
+            .blksize_overrides
+            .iter()
+            .find(|(glob, _)| glob_match(glob, file))
+            .map_or(config.blksize, |(_, blksize)| *blksize),
+    };
+
+    if requested.is_some() || blksize != DEFAULT_BLKSIZE {
+        options.push(("blksize".to_owned(), blksize.to_string()));
+    }
+}
+
+/// Checks the `mtime` and `mode` options of a WRQ writing a file under
+/// `preserve_metadata`, refusing the request if either can't be applied.
+/// Anywhere else they're dropped, so they're neither echoed nor acted on.
+pub(crate) fn negotiate_metadata(
+    config: &Config,
+    writes_file: bool,
+    options: &mut Vec<(String, String)>,
+) -> Result<(), Packet> {
+    if !config.preserve_metadata || !writes_file {
+        options.retain(|(name, _)| name != "mtime" && name != "mode");
+        return Ok(());
+    }
+
+    for (name, value) in options.iter() {
+        let valid = match name.as_str() {
+            "mtime" => mtime_option(value).is_some(),
+            "mode" => mode_option(value).is_some(),
+            _ => true,
+        };
+        if !valid {
+            return Err(Packet::new_error(
+                OPTION_NEGOTIATION,
+                format!("Invalid {}", name),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// An `mtime` option's seconds since the epoch, as a time
+fn mtime_option(value: &str) -> Option<SystemTime> {
+    let secs = value.parse().ok()?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+/// A `mode` option's octal permission bits
+fn mode_option(value: &str) -> Option<u32> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|&mode| mode <= 0o7777)
+}
+
+/// What a WRQ asked its file to be given with options `negotiate_metadata`
+/// let through
+struct UploadMetadata {
+    mtime: Option<SystemTime>,
+    mode: Option<u32>,
+}
+
+impl UploadMetadata {
+    fn from_options(options: &[(String, String)]) -> Self {
+        let value = |name: &str| {
+            options
+                .iter()
+                .find(|(option, _)| option == name)
+                .map(|(_, value)| value.as_str())
+        };
+
+        Self {
+            mtime: value("mtime").and_then(mtime_option),
+            mode: value("mode").and_then(mode_option),
+        }
+    }
+
+    /// Sets the mtime and, on Unix, the executable bits. Everything's been
+    /// written by now, or a later write would move the mtime on.
+    fn apply(&self, file: &fs::File) -> io::Result<()> {
+        if let Some(mtime) = self.mtime {
+            file.set_modified(mtime)?;
+        }
+
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mut permissions = file.metadata()?.permissions();
+            permissions.set_mode(permissions.mode() & !0o111 | mode & 0o111);
+            file.set_permissions(permissions)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Answers a `serverinfo` option, a nonstandard extension, with the
+/// configured banner for the worker to echo in its OACK
+pub(crate) fn negotiate_serverinfo(config: &Config, options: &mut Vec<(String, String)>) {
+    let requested = options.iter().any(|(name, _)| name == "serverinfo");
+    options.retain(|(name, _)| name != "serverinfo");
+
+    if let (true, Some(banner)) = (requested, &config.banner) {
+        options.push(("serverinfo".to_owned(), banner.clone()));
+    }
+}
+
+/// Matches `name` against a pattern where `*` stands for any run of
+/// characters and `?` for exactly one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Position in the pattern just after the last `*`, and in the name where
+    // that star's match currently ends
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the star swallow one more character and retry
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Grows the receive buffer so DATA of the block size a transfer settled on
+/// isn't truncated. It never shrinks, so the allocation is reused.
+pub(crate) fn fit_blksize(buf: &mut Vec<u8>, options: &[(String, String)]) {
+    if let Some(blksize) = blksize(options) {
+        if buf.len() < blksize + 4 {
+            buf.resize(blksize + 4, 0);
+        }
+    }
+}
+
+/// The block size the request's options settled on
+pub(crate) fn blksize(options: &[(String, String)]) -> Option<usize> {
+    options
+        .iter()
+        .find(|(name, _)| name == "blksize")
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// The retransmission timeout the client asked for, with the option to echo
+/// for it. `utimeout`, in microseconds, wins over RFC 2349's `timeout` in
+/// seconds; a value out of range is ignored like an unknown option.
+pub(crate) fn timeout(options: &[(String, String)]) -> Option<((String, String), Duration)> {
+    let value = |name: &str| {
+        options
+            .iter()
+            .find(|(option, _)| option == name)
+            .and_then(|(_, value)| value.parse::<u64>().ok())
+    };
+
+    if let Some(micros @ 10_000..=255_000_000) = value("utimeout") {
+        let option = ("utimeout".to_owned(), micros.to_string());
+        return Some((option, Duration::from_micros(micros)));
+    }
+    if let Some(secs @ 1..=255) = value("timeout") {
+        let option = ("timeout".to_owned(), secs.to_string());
+        return Some((option, Duration::from_secs(secs)));
+    }
+
+    None
+}
+
+/// The `serverinfo` option settled on, ready to echo
+pub(crate) fn serverinfo(options: &[(String, String)]) -> Option<(String, String)> {
+    options
+        .iter()
+        .find(|(name, _)| name == "serverinfo")
+        .cloned()
+}
+
+/// The OACK answering an RRQ that asked with `probe` whether the file exists,
+/// of `size` bytes if it does. The client gets `exists=1` and the `tsize`, or
+/// `exists=0`, and no transfer follows.
+pub(crate) fn probe(options: &[(String, String)], size: Option<u64>) -> Option<Packet> {
+    if !options.iter().any(|(name, _)| name == "probe") {
+        return None;
+    }
+
+    let options = match size {
+        Some(size) => vec![
+            ("exists".to_owned(), "1".to_owned()),
+            ("tsize".to_owned(), size.to_string()),
+        ],
+        None => vec![("exists".to_owned(), "0".to_owned())],
+    };
+
+    Some(Packet::OAck { options })
+}
+
+/// The size of the regular file at `path`, if there is one
+pub(crate) fn file_size(path: &Path) -> Option<u64> {
+    fs::metadata(path)
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+}
+
+/// Takes the `listfmt` option out of an RRQ, returning whether it asked for
+/// the long index listing. Only the index has a format to pick, so it's up to
+/// the caller to put the option back for the worker to echo when honoring it.
+fn take_listfmt(options: &mut Vec<(String, String)>) -> bool {
+    let long = options
+        .iter()
+        .any(|(name, value)| name == "listfmt" && value.eq_ignore_ascii_case("long"));
+    options.retain(|(name, _)| name != "listfmt");
+
+    long
+}
+
+/// The `listfmt` option, if the index is being listed in the long format
+fn listfmt(options: &[(String, String)]) -> Option<(String, String)> {
+    options.iter().find(|(name, _)| name == "listfmt").cloned()
+}
+
+/// The `content-encoding` option, if the file is being sent compressed
+fn content_encoding(options: &[(String, String)]) -> Option<(String, String)> {
+    options
+        .iter()
+        .find(|(name, _)| name == "content-encoding")
+        .cloned()
+}
+
+/// Settles what an RRQ is served from when `name` is missing but `name.gz`
+/// is there, returning the path to send and whether it's to be decompressed
+/// on the way. A client that asked for `content-encoding=gzip` is sent the
+/// compressed file as is, and the option is left for the worker to echo.
+/// Anyone else gets it decompressed by the worker.
+#[cfg(feature = "gzip")]
+fn negotiate_gzip(
+    config: &Config,
+    path: PathBuf,
+    in_memory: bool,
+    options: &mut Vec<(String, String)>,
+) -> (PathBuf, bool) {
+    let raw = options
+        .iter()
+        .any(|(name, value)| name == "content-encoding" && value.eq_ignore_ascii_case("gzip"));
+    options.retain(|(name, _)| name != "content-encoding");
+
+    let mut gz = path.clone().into_os_string();
+    gz.push(".gz");
+    let gz = PathBuf::from(gz);
+
+    if !config.gzip || in_memory || path.exists() || !gz.is_file() {
+        return (path, false);
+    }
+
+    if raw {
+        options.push(("content-encoding".to_owned(), "gzip".to_owned()));
+    }
+
+    (gz, !raw)
+}
+
+/// Without gzip support files are only ever sent as they are
+#[cfg(not(feature = "gzip"))]
+fn negotiate_gzip(
+    _config: &Config,
+    path: PathBuf,
+    _in_memory: bool,
+    options: &mut Vec<(String, String)>,
+) -> (PathBuf, bool) {
+    options.retain(|(name, _)| name != "content-encoding");
+
+    (path, false)
+}
+
+/// The size of the gzipped file at `path` once decompressed, as its trailer
+/// records it. That's modulo 4 GiB, and only covers the last member of a
+/// multi-member file, but it's all there is short of decompressing it.
+fn gunzipped_size(path: &Path) -> Option<u64> {
+    let mut file = fs::File::open(path).ok()?;
+    file.seek(SeekFrom::End(-4)).ok()?;
+    let mut trailer = [0; 4];
+    file.read_exact(&mut trailer).ok()?;
+
+    Some(u32::from_le_bytes(trailer) as u64)
+}
+
+/// Fails a WRQ early if the directory it writes into is missing and can't be
+/// created, or if it declares a `tsize` (RFC 2349) that won't fit in the free
+/// space left there
+pub(crate) fn preflight_write(
+    config: &Config,
+    root: &Path,
+    path: &Path,
+    options: &[(String, String)],
+) -> Result<(), Packet> {
+    let dir = path.parent().unwrap_or(path);
+    if !dir.is_dir() {
+        if !config.create_dirs {
+            return Err(Packet::new_error(FILE_NOT_FOUND, "Directory not found"));
+        }
+        create_dirs(root, dir)?;
+    }
+
+    let tsize = match tsize(options) {
+        Some(tsize) => tsize,
+        None => return Ok(()),
+    };
+
+    match (config.free_space)(dir) {
+        Some(available) if tsize > available => Err(Packet::new_error(
+            DISK_FULL,
+            "Disk full or allocation exceeded",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Creates `dir` and any missing parents. `resolve` has kept the path itself
+/// under `root`, but a symlink among the existing directories could still
+/// lead out of it, so the deepest one that exists is checked first.
+fn create_dirs(root: &Path, dir: &Path) -> Result<(), Packet> {
+    let access_violation = || Packet::new_error(ACCESS_VIOLATION, "Access violation");
+
+    let existing = dir
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .ok_or_else(access_violation)?;
+    let (existing, root) = match (existing.canonicalize(), root.canonicalize()) {
+        (Ok(existing), Ok(root)) => (existing, root),
+        (Err(e), _) | (_, Err(e)) => return Err(Packet::from_io_error(&e)),
+    };
+    if !existing.starts_with(root) {
+        return Err(access_violation());
+    }
+
+    fs::create_dir_all(dir).map_err(|e| Packet::from_io_error(&e))
+}
+
+/// The `windowsize` a client asked for (RFC 7440), if it's a valid one
+pub(crate) fn windowsize(options: &[(String, String)]) -> Option<u16> {
+    options
+        .iter()
+        .find(|(name, _)| name == "windowsize")
+        .and_then(|(_, value)| value.parse().ok())
+        .filter(|&windowsize| windowsize > 0)
+}
+
+/// The transfer size declared by the client, if any
+pub(crate) fn tsize(options: &[(String, String)]) -> Option<u64> {
+    options
+        .iter()
+        .find(|(name, _)| name == "tsize")
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// Confines the process to `root`, which becomes `/`
+#[cfg(unix)]
+pub(crate) fn chroot(root: &Path) -> io::Result<()> {
+    std::os::unix::fs::chroot(root)?;
+    std::env::set_current_dir("/")
+}
+
+#[cfg(not(unix))]
+pub(crate) fn chroot(_root: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "chroot is only supported on Unix",
+    ))
+}
+
+/// Pins `socket` to the network interface `name`, so it only sees traffic
+/// that arrives there
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn bind_to_device<S: std::os::fd::AsRawFd>(socket: &S, name: &str) -> io::Result<()> {
+    // SAFETY: `name` points to `name.len()` readable bytes, the length passed
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr().cast(),
+            name.len() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Without SO_BINDTODEVICE the socket stays bound to its address alone
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn bind_to_device<S>(_socket: &S, name: &str) -> io::Result<()> {
+    eprintln!("Warning: can't pin the socket to {} on this platform", name);
+    Ok(())
+}
+
+/// Space available to unprivileged users on the filesystem holding `path`
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    // SAFETY: `path` is NUL-terminated and `stat` is a valid statvfs to fill
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Free space can't be queried here, so no WRQ is refused for lack of it
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Whether `path` is a named pipe. Opening one blocks until its other end is
+/// opened too, which is left to the transfer's worker.
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &Path) -> bool {
+    false
+}
+
+/// The root to serve `peer` from: the first of `subnet_roots` that holds it,
+/// or else `root`, and within that the subdirectory `mode_subdirs` gives
+/// for `mode`, if any
+pub(crate) fn root_for(config: &Config, root: &Path, peer: IpAddr, mode: &Mode) -> PathBuf {
+    // As in `check_peer`
+    let peer = peer.to_canonical();
+    let root = config
+        .subnet_roots
+        .iter()
+        .find(|(subnet, _)| subnet.contains(&peer))
+        .map_or(root, |(_, root)| root);
+
+    match config.mode_subdirs.iter().find(|(m, _)| m == mode) {
+        Some((_, subdir)) => root.join(subdir),
+        None => root.to_path_buf(),
+    }
+}
+
+/// Maps a requested filename onto a path under `root`. A leading '/' is taken
+/// as relative to the root; anything that would climb out of it is refused,
+/// as is the temporary file of an upload still being written.
+pub(crate) fn resolve(root: &Path, file: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+
+    for component in Path::new(file).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    if path.file_name().is_some_and(is_temp_name) {
+        return None;
+    }
+
+    Some(path)
+}
+
+/// Initial Connection Protocol for reading a file
+/// 1. Host  A  sends  a  "RRQ"  to  host  B  with  source= A's TID,
+///    destination= 69.
+/// 2. Host B sends a "DATA" (with block number= 1) to host  A  with
+///    source= B's TID, destination= A's TID.
+///
+/// RRQ and ACK packets are awknowledged by DATA and ERROR packets
+///
+/// If any of the request's options are accepted, step 2 becomes an "OACK"
+/// that the client acknowledges with an ACK of block 0 before the first DATA
+/// is sent (RFC 2347).
+fn read_process<T: Transport>(
+    t: &Transfer<T>,
+    file: PathBuf,
+    mode: Mode,
+    io_buffer: usize,
+) -> TransferResult {
+    outcome(t, read_transfer(t, file, mode, io_buffer))
+}
+
+/// Folds a worker's I/O errors into how its transfer ended. A client that
+/// went away is the transfer ending, not a server error: its host answers our
+/// next datagram with an ICMP port-unreachable, which the socket reports on a
+/// later `send_to`. Any other error, such as a full disk or a failed read, is
+/// sent to the client so it doesn't wait out its timeouts for nothing.
+fn outcome<T: Transport>(t: &Transfer<T>, res: io::Result<TransferResult>) -> TransferResult {
+    match res {
+        Ok(res) => res,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+            ) =>
+        {
+            TransferResult::PeerGone
+        }
+        Err(e) => {
+            let _ = t
+                .socket
+                .send_to(Packet::from_io_error(&e).serialize().as_slice(), t.dst);
+
+            TransferResult::Failed(e)
+        }
+    }
+}
+
+/// Runs a transfer's worker on its own thread. Should it panic, the peer is
+/// sent an ERROR rather than left to time out, at wherever it has got to by
+/// then.
+fn spawn_worker<T, F>(socket: Arc<PeerSocket<T>>, id: u64, work: F) -> JoinHandle<()>
+where
+    T: Transport + 'static,
+    F: FnOnce() + Send + 'static,
+{
+    thread::spawn(move || {
+        // The worker owns all it touches, and drops it all on the way out
+        if panic::catch_unwind(AssertUnwindSafe(work)).is_err() {
+            let dst = *socket.peer.lock().unwrap();
+            eprintln!("{}", tagged(id, format!("Worker for {} panicked", dst)));
+            let err = Packet::new_error(SEE_MSG, "Internal error");
+            let _ = socket.send_to(err.serialize().as_slice(), dst);
+        }
+    })
+}
+
+/// Logs how a transfer ended, unless it simply completed, and counts it
+fn report<T>(t: &Transfer<T>, res: TransferResult) {
+    t.entry.finish(&res);
+
+    if let Some(line) = outcome_line(t.entry.id, t.dst, &res) {
+        eprintln!("{}", line);
+    }
+}
+
+/// What `report` logs for a transfer that ended as `res`
+fn outcome_line(id: u64, dst: SocketAddr, res: &TransferResult) -> Option<String> {
+    let line = match res {
+        TransferResult::Completed => return None,
+        TransferResult::Aborted { code, msg } => abort_line(dst, *code, msg),
+        TransferResult::TimedOut => format!("Gave up waiting to hear from {}", dst),
+        TransferResult::PeerGone => format!("Peer {} has gone away", dst),
+        TransferResult::Rejected { code, msg } => {
+            format!("Ended the transfer with {}, error {}: {}", dst, code, msg)
+        }
+        TransferResult::Failed(e) => format!("Error: {}", e),
+    };
+
+    Some(tagged(id, line))
+}
+
+/// A log line about the transfer `id`, marked as such so the lines of
+/// transfers running at once can be told apart
+fn tagged(id: u64, line: String) -> String {
+    format!("[transfer {}] {}", id, line)
+}
+
+/// How a transfer ends when the peer sends an ERROR
+fn aborted(code: u16, msg: Cow<'static, str>) -> TransferResult {
+    TransferResult::Aborted {
+        code,
+        msg: msg.into_owned(),
+    }
+}
+
+/// What's logged for an ERROR from `dst` that ended its transfer, the same
+/// for both servers
+pub(crate) fn abort_line(dst: SocketAddr, code: u16, msg: &str) -> String {
+    format!("{} ended the transfer with error {}: {}", dst, code, msg)
+}
+
+/// The line `request_log` gets for an accepted request, as `key=value`
+/// fields with the filename and options quoted
+fn request_line(
+    now: SystemTime,
+    id: u64,
+    peer: SocketAddr,
+    op_code: u16,
+    file: &str,
+    mode: &Mode,
+    options: &[(String, String)],
+) -> String {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let options = options
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!(
+        "time={}.{:03} id={} peer={} op={} file={:?} mode={} options={:?}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_millis(),
+        id,
+        peer,
+        if op_code == READ_OPCODE { "RRQ" } else { "WRQ" },
+        file,
+        mode.as_str(),
+        options
+    )
+}
+
+/// Sends an ERROR ending the transfer, and says so
+fn reject<T: Transport>(
+    socket: &T,
+    dst: SocketAddr,
+    code: u16,
+    msg: &'static str,
+) -> io::Result<TransferResult> {
+    socket.send_to(Packet::new_error(code, msg).serialize().as_slice(), dst)?;
+
+    Ok(TransferResult::Rejected {
+        code,
+        msg: msg.to_owned(),
+    })
+}
+
+/// Ends a transfer whose file, or whatever stands in for it, couldn't be
+/// opened, telling the client why
+fn failed_to_open<T: Transport>(t: &Transfer<T>, e: io::Error) -> io::Result<TransferResult> {
+    t.socket
+        .send_to(Packet::from_io_error(&e).serialize().as_slice(), t.dst)?;
+
+    Ok(TransferResult::Failed(e))
+}
+
+fn read_transfer<T: Transport>(
+    t: &Transfer<T>,
+    file: PathBuf,
+    mode: Mode,
+    io_buffer: usize,
+) -> io::Result<TransferResult> {
+    let file = match fs::File::open(file) {
+        Ok(f) => f,
+        Err(e) => return failed_to_open(t, e),
+    };
+    // A FIFO or other special file has no length to go by, so it's read
+    // until it ends
+    let metadata = file.metadata()?;
+    let size = Some(metadata.len()).filter(|_| metadata.is_file());
+
+    let source = BufReader::with_capacity(io_buffer, file);
+
+    send_source(t, source, size, mode)
+}
+
+/// Serves a file the way `read_process` does, but copies its blocks out of a
+/// memory map. The map is as long as the file was when it was opened, so
+/// like `read_process` this serves a snapshot of its length. A file that
+/// can't be mapped is read as usual.
+#[cfg(feature = "mmap")]
+fn mmap_process<T: Transport>(
+    t: &Transfer<T>,
+    file: PathBuf,
+    mode: Mode,
+    io_buffer: usize,
+) -> TransferResult {
+    let map = fs::File::open(&file).and_then(|f| {
+        // SAFETY: the read lock held for the transfer keeps this server's
+        // own WRQs off the file; anything else truncating it is ruled out
+        // by the caveat on `Config::mmap`
+        unsafe { memmap2::Mmap::map(&f) }
+    });
+    let map = match map {
+        Ok(map) => map,
+        Err(_) => return read_process(t, file, mode, io_buffer),
+    };
+
+    let size = Some(map.len() as u64);
+    outcome(t, send_source(t, io::Cursor::new(map), size, mode))
+}
+
+/// Serves the gzipped `file` decompressed as it's read. How long it comes out
+/// isn't known until it ends, so like a FIFO it's sent until then, and
+/// `tsize`, `range` and `checksum` go unanswered.
+#[cfg(feature = "gzip")]
+fn gunzip_process<T: Transport>(
+    t: &Transfer<T>,
+    file: PathBuf,
+    mode: Mode,
+    io_buffer: usize,
+) -> TransferResult {
+    outcome(t, gunzip_transfer(t, file, mode, io_buffer))
+}
+
+#[cfg(feature = "gzip")]
+fn gunzip_transfer<T: Transport>(
+    t: &Transfer<T>,
+    file: PathBuf,
+    mode: Mode,
+    io_buffer: usize,
+) -> io::Result<TransferResult> {
+    use flate2::read::GzDecoder;
+
+    let file = match fs::File::open(file) {
+        Ok(f) => f,
+        Err(e) => return failed_to_open(t, e),
+    };
+    let source = Unseekable(GzDecoder::new(BufReader::with_capacity(io_buffer, file)));
+
+    send_source(t, source, None, mode)
+}
+
+/// A reader for `send_source` with no `size`, which it never seeks
+#[cfg(feature = "gzip")]
+struct Unseekable<R>(R);
+
+#[cfg(feature = "gzip")]
+impl<R: Read> Read for Unseekable<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl<R> Seek for Unseekable<R> {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "source can't be seeked",
+        ))
+    }
+}
+
+/// Serves an RRQ from what the `open_read_fn` hook opens for `file`
+fn hooked_read_process<T: Transport>(
+    t: &Transfer<T>,
+    open: &OpenRead,
+    file: &str,
+    mode: Mode,
+) -> TransferResult {
+    outcome(t, hooked_read_transfer(t, open, file, mode))
+}
+
+fn hooked_read_transfer<T: Transport>(
+    t: &Transfer<T>,
+    open: &OpenRead,
+    file: &str,
+    mode: Mode,
+) -> io::Result<TransferResult> {
+    let mut source = match open(file) {
+        Ok(source) => source,
+        Err(e) => return failed_to_open(t, e),
+    };
+    let size = Some(source.seek(SeekFrom::End(0))?);
+    source.rewind()?;
+
+    send_source(t, source, size, mode)
+}
+
+/// Serves contents held in memory the way `read_process` serves a file
+fn memory_process<T: Transport>(
+    t: &Transfer<T>,
+    contents: Arc<[u8]>,
+    mode: Mode,
+) -> TransferResult {
+    let size = Some(contents.len() as u64);
+    let source = io::Cursor::new(contents);
+
+    outcome(t, send_source(t, source, size, mode))
+}
+
+/// Serves the listing of `root` asked for by an RRQ of the index, the way
+/// `memory_process` serves contents held in memory. It's a snapshot of the
+/// root as the transfer starts.
+fn index_process<T: Transport>(
+    t: &Transfer<T>,
+    config: &Config,
+    root: &Path,
+    long: bool,
+    mode: Mode,
+) -> TransferResult {
+    outcome(t, index_transfer(t, config, root, long, mode))
+}
+
+fn index_transfer<T: Transport>(
+    t: &Transfer<T>,
+    config: &Config,
+    root: &Path,
+    long: bool,
+    mode: Mode,
+) -> io::Result<TransferResult> {
+    let listing = match index(config, root, long) {
+        Ok(listing) => listing,
+        Err(e) => return failed_to_open(t, e),
+    };
+
+    let size = Some(listing.len() as u64);
+    if let Some(oack) = probe(&t.options, size) {
+        t.socket.send_to(oack.serialize().as_slice(), t.dst)?;
+
+        return Ok(TransferResult::Completed);
+    }
+
+    send_source(t, io::Cursor::new(listing), size, mode)
+}
+
+/// Negotiates the request's options for a `size` byte `source`, then sends
+/// it from its start, or from where the `range` option asks.
+///
+/// `size` is a snapshot taken as the transfer starts and nothing past it is
+/// sent, so a file that grows meanwhile is served as it was. One that shrinks
+/// ends early: its first short read makes the final block.
+///
+/// Without a `size`, as for a FIFO, the source is sent until it ends and
+/// never seeked, so `tsize`, `range` and `checksum` go unanswered.
+fn send_source<T: Transport, R: Read + Seek>(
+    t: &Transfer<T>,
+    mut source: R,
+    mut size: Option<u64>,
+    mode: Mode,
+) -> io::Result<TransferResult> {
+    let options = &t.options;
+    if mode == Mode::Mail {
+        return reject(
+            &*t.socket,
+            t.dst,
+            ILLEGAL_OP,
+            "Mail mode can't be used to read",
+        );
+    }
+
+    // Options we agreed to, echoed back to the client in an OACK
+    let mut accepted = Vec::new();
+    let mut len = size;
+
+    let blksize = match blksize(options) {
+        Some(blksize) => {
+            accepted.push(("blksize".to_owned(), blksize.to_string()));
+            blksize
+        }
+        None => DEFAULT_BLKSIZE,
+    };
+
+    let range = options.iter().find(|(name, _)| name == "range");
+    if let (Some((_, range)), Some(whole)) = (range, size) {
+        match parse_range(range, whole) {
+            Some((start, end)) => {
+                source.seek(SeekFrom::Start(start))?;
+                len = Some(end - start);
+                size = len;
+                accepted.push(("range".to_owned(), format!("{}-{}", start, end)));
+            }
+            None => return reject(&*t.socket, t.dst, OPTION_NEGOTIATION, "Invalid range"),
+        }
+    }
+
+    // The client asks how much it's about to receive (RFC 2349)
+    if let (Some(_), Some(size)) = (tsize(options), size) {
+        accepted.push(("tsize".to_owned(), size.to_string()));
+    }
+    let timeout = match timeout(options) {
+        Some((option, timeout)) => {
+            accepted.push(option);
+            timeout
+        }
+        None => ACK_TIMEOUT,
+    };
+    accepted.extend(content_encoding(options));
+    accepted.extend(serverinfo(options));
+    accepted.extend(listfmt(options));
+    if let Some(len) = len {
+        accepted.extend(checksum(options, &mut source, len, &mode)?);
+
+        // Netascii grows the file on the wire, so its length isn't known up
+        // front
+        if mode == Mode::Octet {
+            t.entry.set_total(len);
+        }
+    }
+
+    if !accepted.is_empty() {
+        if let Some(res) = send_oack(t, accepted, timeout)? {
+            return Ok(res);
+        }
+    }
+
+    let reader = source.take(len.unwrap_or(u64::MAX));
+    match mode {
+        Mode::NetAscii => {
+            let reader = NetAsciiReader::new(BufReader::new(reader));
+            send_file(t, reader, blksize, timeout)
+        }
+        _ => send_file(t, reader, blksize, timeout),
+    }
+}
+
+/// The SHA-256 of the next `len` bytes of `source` as they'll be sent, for a
+/// client that asked with `checksum=sha256`. The OACK goes out before any
+/// DATA, so this takes a pass over the source up front and seeks back.
+#[cfg(feature = "checksum")]
+fn checksum<R: Read + Seek>(
+    options: &[(String, String)],
+    source: &mut R,
+    len: u64,
+    mode: &Mode,
+) -> io::Result<Option<(String, String)>> {
+    use sha2::{Digest, Sha256};
+
+    let requested = options
+        .iter()
+        .any(|(name, value)| name == "checksum" && value.eq_ignore_ascii_case("sha256"));
+    if !requested {
+        return Ok(None);
+    }
+
+    let start = source.stream_position()?;
+    let mut hasher = Sha256::new();
+    let mut reader = (&mut *source).take(len);
+    match mode {
+        Mode::NetAscii => io::copy(
+            &mut NetAsciiReader::new(BufReader::new(reader)),
+            &mut hasher,
+        )?,
+        _ => io::copy(&mut reader, &mut hasher)?,
+    };
+    source.seek(SeekFrom::Start(start))?;
+
+    let digest: String = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    Ok(Some(("checksum".to_owned(), format!("sha256:{}", digest))))
+}
+
+/// Without checksum support the option is ignored, like any unknown one
+#[cfg(not(feature = "checksum"))]
+fn checksum<R: Read + Seek>(
+    _options: &[(String, String)],
+    _source: &mut R,
+    _len: u64,
+    _mode: &Mode,
+) -> io::Result<Option<(String, String)>> {
+    Ok(None)
+}
+
+/// Lists the files directly under `root` that a client would be allowed to
+/// read, one name per line, sorted. The `long` format puts the size in bytes
+/// and the mtime in seconds since the epoch before each name.
+fn index(config: &Config, root: &Path, long: bool) -> io::Result<Vec<u8>> {
+    let mut names = BTreeMap::new();
+
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        // Uploads still being written are left out, as resolve refuses them
+        if !entry.file_type()?.is_file() || is_temp_name(&entry.file_name()) {
+            continue;
+        }
+
+        // Names that aren't UTF-8 can't be requested anyway
+        if let Ok(name) = entry.file_name().into_string() {
+            if authorize(config, READ_OPCODE, &name).is_ok() {
+                names.insert(name, entry.metadata()?);
+            }
+        }
+    }
+
+    let mut listing = Vec::new();
+    for (name, metadata) in names {
+        if long {
+            let mtime = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            write!(listing, "{} {} ", metadata.len(), mtime.as_secs())?;
+        }
+        listing.extend_from_slice(name.as_bytes());
+        listing.push(b'\n');
+    }
+
+    Ok(listing)
+}
+
+/// Parses a "start-end" byte range, where `end` is exclusive and both must
+/// lie within a file of `size` bytes
+pub(crate) fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.split_once('-')?;
+    let start: u64 = start.trim().parse().ok()?;
+    let end: u64 = end.trim().parse().ok()?;
+
+    if start > end || end > size {
+        return None;
+    }
+
+    Some((start, end))
+}
+
+/// Sends an OACK for the options we accepted and waits for the client to
+/// acknowledge it with ACK 0, which stands in for the first ACK/DATA exchange.
+/// The OACK is resent every `timeout` while it goes unanswered, as a block
+/// would be. Returns how the transfer ended if the client refused the options
+/// or went away instead.
+fn send_oack<T: Transport>(
+    t: &Transfer<T>,
+    options: Vec<(String, String)>,
+    timeout: Duration,
+) -> io::Result<Option<TransferResult>> {
+    let (socket, dst) = (&*t.socket, t.dst);
+    let oack = Packet::OAck { options }.serialize();
+    socket.send_to(&oack, dst)?;
+
+    let mut retransmits = 0;
+    loop {
+        let e = match recv_before(&t.rx, Some(timeout), t.deadline) {
+            Ok(Some(e)) => e,
+            Ok(None) => return too_long(socket, dst).map(Some),
+            Err(RecvTimeoutError::Timeout) if retransmits < MAX_RETRANSMITS => {
+                retransmits += 1;
+                socket.send_to(&oack, dst)?;
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout) => return Ok(Some(TransferResult::TimedOut)),
+            Err(RecvTimeoutError::Disconnected) => return Ok(Some(TransferResult::PeerGone)),
+        };
+
+        match e {
+            Packet::Ack { block: 0 } => return Ok(None),
+            Packet::Error { code, msg } => return Ok(Some(aborted(code, msg))),
+            // The request again, so the OACK was lost
+            Packet::Request { .. } => {
+                socket.send_to(&oack, dst)?;
+            }
+            _ => continue,
+        }
+    }
+}
+
+/// Waits for the next packet from the peer, for up to `timeout` if there is
+/// one, but never past `deadline`. Once that has passed this is `Ok(None)`,
+/// even if packets are still queued, so a steady client can't outlast it.
+fn recv_before(
+    rx: &Receiver<Packet>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+) -> Result<Option<Packet>, RecvTimeoutError> {
+    let now = Instant::now();
+    let wait = match deadline {
+        Some(deadline) if now >= deadline => return Ok(None),
+        Some(deadline) => Some(timeout.map_or(deadline - now, |t| t.min(deadline - now))),
+        None => timeout,
+    };
+
+    let res = match wait {
+        Some(wait) => rx.recv_timeout(wait),
+        None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+    };
+    match res {
+        Err(RecvTimeoutError::Timeout) if deadline.is_some_and(|d| Instant::now() >= d) => Ok(None),
+        res => res.map(Some),
+    }
+}
+
+/// Aborts a transfer that ran past `Config::max_transfer_duration`
+fn too_long<T: Transport>(socket: &T, dst: SocketAddr) -> io::Result<TransferResult> {
+    reject(socket, dst, SEE_MSG, "Transfer took too long")
+}
+
+/// Reads from `reader` until `buf` is full or it runs out. A single `read`
+/// may come back short without being at the end, so only a short count from
+/// here means the source is exhausted.
+pub(crate) fn fill_block<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(len)
+}
+
+/// Streams `reader` to the peer one block at a time, resending a block that
+/// isn't acknowledged within `timeout`.
+///
+/// Only the block currently in flight is held in memory, in buffers from the
+/// transfer's pool, so a file of any size is served with the same footprint.
+/// The transfer ends with the first block shorter than `blksize`, which is
+/// empty if the length is a multiple of the block size.
+fn send_file<T: Transport, R: Read>(
+    t: &Transfer<T>,
+    mut reader: R,
+    blksize: usize,
+    timeout: Duration,
+) -> io::Result<TransferResult> {
+    let Transfer {
+        socket,
+        dst,
+        rx,
+        deadline,
+        pool,
+        entry,
+        ..
+    } = t;
+    let (socket, dst) = (&**socket, *dst);
+    let mut current_block: u16 = 1;
+    let mut data = pool.take(blksize);
+    let mut res = pool.take(blksize + 4);
+
+    loop {
+        // Read file into buffer
+        data.resize(blksize, 0);
+        let len = fill_block(&mut reader, &mut data)?;
+
+        // Send data, taking the buffer back from the packet for the next block
+        let packet = Packet::new_data(current_block, mem::take(&mut *data), len);
+        packet.serialize_into(&mut res);
+        if let Packet::Data { data: block, .. } = packet {
+            *data = block;
+        }
+        entry.add_bytes(len);
+
+        socket.send_to(&res, dst)?;
+
+        // Wait for ACK, resending the packet we already built if it's slow to
+        // come. The file has moved on, so it mustn't be read again.
+        let mut retransmits = 0;
+        'recv: loop {
+            let e = match recv_before(rx, Some(timeout), *deadline) {
+                Ok(Some(e)) => e,
+                Ok(None) => return too_long(socket, dst),
+                Err(RecvTimeoutError::Timeout) if retransmits < MAX_RETRANSMITS => {
+                    retransmits += 1;
+                    socket.send_to(&res, dst)?;
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) => return Ok(TransferResult::TimedOut),
+                Err(RecvTimeoutError::Disconnected) => return Ok(TransferResult::PeerGone),
+            };
+
+            match e {
+                Packet::Data {
+                    block: _,
+                    data: _,
+                    len: _,
+                } => {
+                    // Since this is a read request we're not expecting data packets
+                    // from the client
+                    continue;
+                }
+                Packet::Ack { block } => {
+                    // Need to make sure this block matches what we sent
+                    // Else keep waiting
+                    if block == current_block {
+                        // Block numbers roll over for files bigger than 32 MiB
+                        current_block = current_block.wrapping_add(1);
+                        break 'recv;
+                    }
+                }
+                // The RRQ again, so block 1 was lost; it's resent now rather
+                // than when the ACK times out
+                Packet::Request { .. } if current_block == 1 => {
+                    socket.send_to(&res, dst)?;
+                }
+                Packet::Request { .. } => continue,
+                Packet::Error { code, msg } => return Ok(aborted(code, msg)),
+                // Only an OACK for now, which `run` turns into an ERROR
+                // before it gets here
+                _ => return reject(socket, dst, ILLEGAL_OP, "Unexpected packet"),
+            }
+        }
+
+        if len < blksize {
+            return Ok(TransferResult::Completed);
+        }
+    }
+}
+
+/// Initial Connection Protocol for writing a file
+/// 1. Host A sends  a  "WRQ"  to  host  B  with  source=  A's  TID,
+///    destination= 69.
+/// 2. Host  B  sends  a "ACK" (with block number= 0) to host A with
+///    source= B's TID, destination= A's TID.
+///
+/// WRQ and DATA packets are awknowledged by ACK and ERROR packets
+///
+/// If any of the WRQ's options are accepted, step 2 is an "OACK" echoing
+/// them instead, to which the client replies with block 1 (RFC 2347). With a
+/// `windowsize` (RFC 7440) only every that many blocks are ACKed.
+fn write_process<T: Transport>(
+    t: &Transfer<T>,
+    file: PathBuf,
+    io_buffer: usize,
+    max_blocks: Option<u64>,
+    lock: Option<FileLock>,
+) -> TransferResult {
+    outcome(t, write_transfer(t, file, io_buffer, max_blocks, lock))
+}
+
+fn write_transfer<T: Transport>(
+    t: &Transfer<T>,
+    file: PathBuf,
+    io_buffer: usize,
+    max_blocks: Option<u64>,
+    lock: Option<FileLock>,
+) -> io::Result<TransferResult> {
+    // A FIFO is written through as it is; renaming over it would replace it
+    // with a regular file that nothing reads
+    if is_fifo(&file) {
+        let fifo = match fs::OpenOptions::new().write(true).open(file) {
+            Ok(fifo) => fifo,
+            Err(e) => return failed_to_open(t, e),
+        };
+        let writer = BufWriter::with_capacity(io_buffer, fifo);
+
+        let finish = move |mut writer: BufWriter<fs::File>| {
+            writer.flush()?;
+            drop(lock);
+            Ok(())
+        };
+
+        return receive_into(t, writer, finish, max_blocks);
+    }
+
+    let (temp, file) = match TempFile::create(file, t.entry.id) {
+        Ok(created) => created,
+        Err(e) => return failed_to_open(t, e),
+    };
+
+    let writer = BufWriter::with_capacity(io_buffer, file);
+
+    // The file takes its name once it's whole, before the final ACK tells
+    // the client so. Nothing more is written to it after that, so the lock
+    // goes with it rather than waiting out the dally.
+    let metadata = UploadMetadata::from_options(&t.options);
+    let commit = move |mut writer: BufWriter<fs::File>| {
+        writer.flush()?;
+        metadata.apply(writer.get_ref())?;
+        drop(writer);
+        temp.commit()?;
+        drop(lock);
+        Ok(())
+    };
+
+    receive_into(t, writer, commit, max_blocks)
+}
+
+/// Takes a WRQ for the log sink, logging what it sends line by line to
+/// `out`, or to stderr without one
+fn log_sink_process<T: Transport>(
+    t: &Transfer<T>,
+    out: Option<Arc<Mutex<dyn Write + Send>>>,
+    max_blocks: Option<u64>,
+) -> TransferResult {
+    let sink = LogSink::new(SinkOut(out), t.dst);
+
+    outcome(
+        t,
+        receive_into(t, sink, |mut sink| sink.flush(), max_blocks),
+    )
+}
+
+/// Takes a WRQ into what the `open_write_fn` hook opens for `file`
+fn hooked_write_process<T: Transport>(
+    t: &Transfer<T>,
+    open: &OpenWrite,
+    file: &str,
+    max_blocks: Option<u64>,
+) -> TransferResult {
+    outcome(t, hooked_write_transfer(t, open, file, max_blocks))
+}
+
+fn hooked_write_transfer<T: Transport>(
+    t: &Transfer<T>,
+    open: &OpenWrite,
+    file: &str,
+    max_blocks: Option<u64>,
+) -> io::Result<TransferResult> {
+    let writer = match open(file) {
+        Ok(writer) => writer,
+        Err(e) => return failed_to_open(t, e),
+    };
+
+    receive_into(t, writer, |mut writer| writer.flush(), max_blocks)
+}
+
+/// Runs a WRQ, writing the blocks it receives to `writer` in order and
+/// handing it to `finish` once the final one is in, before it's ACKed
+fn receive_into<T: Transport, W: Write>(
+    t: &Transfer<T>,
+    mut writer: W,
+    finish: impl FnOnce(W) -> io::Result<()>,
+    max_blocks: Option<u64>,
+) -> io::Result<TransferResult> {
+    let Transfer {
+        socket,
+        dst,
+        rx,
+        options,
+        deadline,
+        entry,
+        ..
+    } = t;
+    let (socket, dst) = (&**socket, *dst);
+
+    // Options we agreed to, echoed back to the client in an OACK
+    let mut accepted = Vec::new();
+
+    let blksize = match blksize(options) {
+        Some(blksize) => {
+            accepted.push(("blksize".to_owned(), blksize.to_string()));
+            blksize
+        }
+        None => DEFAULT_BLKSIZE,
+    };
+    // The size the client declared (RFC 2349), which it mustn't go over
+    let declared = tsize(options);
+    if let Some(tsize) = declared {
+        accepted.push(("tsize".to_owned(), tsize.to_string()));
+        entry.set_total(tsize);
+    }
+    // Blocks the client may send before waiting for an ACK (RFC 7440)
+    let windowsize = match windowsize(options) {
+        Some(windowsize) => {
+            accepted.push(("windowsize".to_owned(), windowsize.to_string()));
+            windowsize
+        }
+        None => 1,
+    };
+    let timeout = match timeout(options) {
+        Some((option, timeout)) => {
+            accepted.push(option);
+            timeout
+        }
+        None => ACK_TIMEOUT,
+    };
+    accepted.extend(serverinfo(options));
+    // Left in only if `negotiate_metadata` accepted them
+    accepted.extend(
+        options
+            .iter()
+            .filter(|(name, _)| name == "mtime" || name == "mode")
+            .cloned(),
+    );
+
+    // Send ack, or an oack if there are options to echo
+    let mut current_block = 0;
+    let res = if accepted.is_empty() {
+        Packet::new_ack(current_block)
+    } else {
+        Packet::OAck { options: accepted }
+    }
+    .serialize();
+
+    socket.send_to(&res, dst)?;
+    current_block += 1;
+
+    // Unlike the block number this doesn't wrap, so it can cap the transfer
+    let mut blocks_written: u64 = 0;
+    let mut received: u64 = 0;
+    // Blocks written since the last ACK, and whether a gap in the window has
+    // been reported that the client hasn't yet resent from
+    let mut unacked: u16 = 0;
+    let mut gap = false;
+    // Timeouts in a row without a new block
+    let mut retransmits = 0;
+
+    loop {
+        let e = match recv_before(rx, Some(timeout), *deadline) {
+            Ok(Some(e)) => e,
+            Ok(None) => return too_long(socket, dst),
+            // The client may not have heard our last ACK, so it's sent again:
+            // the first one, or the OACK, until any block has been written
+            Err(RecvTimeoutError::Timeout) if retransmits < MAX_RETRANSMITS => {
+                retransmits += 1;
+                if blocks_written == 0 {
+                    socket.send_to(&res, dst)?;
+                } else {
+                    let ack = Packet::new_ack(current_block.wrapping_sub(1));
+                    socket.send_to(ack.serialize().as_slice(), dst)?;
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout) => return Ok(TransferResult::TimedOut),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let last = e.is_final_data(blksize);
+
+        match e {
+            Packet::Data {
+                block,
+                data,
+                len: _,
+            } => {
+                // Blocks are written strictly in order. A repeat of the last
+                // block we wrote means the client is still waiting on its
+                // ACK: ACK it again, but don't write it twice. A block further
+                // on in the window means one went missing, so ACK the last
+                // one in order and the client resends from there (RFC 7440).
+                // Anything else out of sequence is dropped.
+                if block == current_block.wrapping_sub(1) {
+                    socket.send_to(Packet::new_ack(block).serialize().as_slice(), dst)?;
+                    unacked = 0;
+                    continue;
+                }
+                if block != current_block {
+                    if !gap && block.wrapping_sub(current_block) < windowsize {
+                        let ack = Packet::new_ack(current_block.wrapping_sub(1));
+                        socket.send_to(ack.serialize().as_slice(), dst)?;
+                        unacked = 0;
+                        gap = true;
+                    }
+                    continue;
+                }
+                gap = false;
+
+                if max_blocks.is_some_and(|max| blocks_written >= max) {
+                    return reject(socket, dst, DISK_FULL, "Too many blocks");
+                }
+                blocks_written += 1;
+                retransmits = 0;
+
+                received += data.len() as u64;
+                if declared.is_some_and(|tsize| received > tsize) {
+                    return reject(socket, dst, ILLEGAL_OP, "More data than declared tsize");
+                }
+
+                // Write to file
+                writer.write_all(&data)?;
+                entry.add_bytes(data.len());
+
+                if last {
+                    finish(writer)?;
+
+                    // The transfer is done once the data is in place; what's
+                    // left only makes sure the client sees the final ACK
+                    entry.finish(&TransferResult::Completed);
+                    entry.remove();
+
+                    socket.send_to(Packet::new_ack(current_block).serialize().as_slice(), dst)?;
+
+                    if let Some(tsize) = declared.filter(|&tsize| received < tsize) {
+                        let line = format!(
+                            "{} sent {} bytes, short of its declared tsize of {}",
+                            dst, received, tsize
+                        );
+                        eprintln!("{}", tagged(entry.id, line));
+                    }
+
+                    // Only stop once the client has had a chance to see that
+                    // ACK
+                    return dally(t, block);
+                }
+
+                // Only the end of a window, or of the file, is ACKed
+                unacked += 1;
+                if unacked == windowsize {
+                    socket.send_to(Packet::new_ack(current_block).serialize().as_slice(), dst)?;
+                    unacked = 0;
+                }
+
+                current_block = current_block.wrapping_add(1);
+            }
+            Packet::Ack { block: _ } => {
+                // Since this is a write request we're not expecting ack packets
+                // from the client
+                continue;
+            }
+            // The WRQ again, its ACK or OACK lost. Until any DATA arrives
+            // that's answered as before; after, the client has plainly heard
+            // it.
+            Packet::Request { .. } if blocks_written == 0 => {
+                socket.send_to(&res, dst)?;
+            }
+            Packet::Request { .. } => continue,
+            Packet::Error { code, msg } => return Ok(aborted(code, msg)),
+            // As in `send_file`
+            _ => return reject(socket, dst, ILLEGAL_OP, "Unexpected packet"),
+        }
+    }
+
+    Ok(TransferResult::PeerGone)
+}
+
+/// The longest line `LogSink` holds on to. Past this it's logged as it is
+/// and the rest goes on the next line, so a client that never sends a
+/// newline can't make the server buffer its whole upload.
+const LOG_LINE_MAX: usize = 4096;
+
+/// Logs what's written to it a line at a time, each prefixed with the peer
+/// it came from. Anything that isn't UTF-8 is logged lossily.
+struct LogSink<W: Write> {
+    out: W,
+    peer: SocketAddr,
+    line: Vec<u8>,
+}
+
+impl<W: Write> LogSink<W> {
+    fn new(out: W, peer: SocketAddr) -> Self {
+        Self {
+            out,
+            peer,
+            line: Vec::new(),
+        }
+    }
+
+    fn log_line(&mut self) -> io::Result<()> {
+        let line = self.line.strip_suffix(b"\r").unwrap_or(&self.line);
+        // In one write, so it isn't interleaved with others sharing `out`
+        let line = format!("{}: {}\n", self.peer, String::from_utf8_lossy(line));
+        self.out.write_all(line.as_bytes())?;
+        self.line.clear();
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for LogSink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte == b'\n' {
+                self.log_line()?;
+            } else {
+                self.line.push(byte);
+                if self.line.len() == LOG_LINE_MAX {
+                    self.log_line()?;
+                }
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Logs a last line that didn't end in a newline
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.line.is_empty() {
+            self.log_line()?;
+        }
+
+        self.out.flush()
+    }
+}
+
+/// Where the log sink writes: the configured `log_sink_writer`, or stderr
+struct SinkOut(Option<Arc<Mutex<dyn Write + Send>>>);
+
+impl Write for SinkOut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &self.0 {
+            Some(out) => out.lock().unwrap().write(buf),
+            None => io::stderr().write(buf),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match &self.0 {
+            Some(out) => out.lock().unwrap().write_all(buf),
+            None => io::stderr().write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.0 {
+            Some(out) => out.lock().unwrap().flush(),
+            None => io::stderr().flush(),
+        }
+    }
+}
+
+/// How many names `TempFile::create` tries before giving up
+const TEMP_FILE_ATTEMPTS: usize = 8;
+
+/// A WRQ's file while it's being written, kept under a hidden name next to
+/// where it belongs. `commit` moves it into place; dropped before that, as
+/// when the transfer fails or its worker panics, it's removed, so a failed
+/// write leaves neither half a file nor the one it would have replaced.
+///
+/// The name is `.{name}.{pid}-{transfer}-{random}.part`, which no client can
+/// guess or ask for (see `resolve`), and the file is created new so nothing
+/// already there is truncated.
+struct TempFile {
+    path: PathBuf,
+    dest: PathBuf,
+    committed: bool,
+}
+
+impl TempFile {
+    fn create(dest: PathBuf, transfer: u64) -> io::Result<(Self, fs::File)> {
+        let name = dest.file_name().unwrap_or_default().to_string_lossy();
+
+        let mut attempts = 0;
+        let (path, file) = loop {
+            let random = RandomState::new().build_hasher().finish();
+            let path = dest.with_file_name(format!(
+                ".{}.{}-{}-{:016x}.part",
+                name,
+                std::process::id(),
+                transfer,
+                random
+            ));
+
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => break (path, file),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    attempts += 1;
+                    if attempts == TEMP_FILE_ATTEMPTS {
+                        return Err(e);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        // The file it replaces keeps its permissions
+        if let Ok(existing) = fs::metadata(&dest) {
+            file.set_permissions(existing.permissions())?;
+        }
+
+        let temp = TempFile {
+            path,
+            dest,
+            committed: false,
+        };
+
+        Ok((temp, file))
+    }
+
+    fn commit(mut self) -> io::Result<()> {
+        fs::rename(&self.path, &self.dest)?;
+        self.committed = true;
+
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Whether `name` is one `TempFile::create` would choose
+fn is_temp_name(name: &OsStr) -> bool {
+    let tag = name
+        .to_str()
+        .and_then(|name| name.strip_prefix('.'))
+        .and_then(|name| name.strip_suffix(".part"))
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, tag)| tag);
+
+    match tag {
+        Some(tag) => {
+            let parts: Vec<&str> = tag.split('-').collect();
+            parts.len() == 3
+                && parts
+                    .iter()
+                    .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_hexdigit()))
+        }
+        None => false,
+    }
+}
+
+/// Lingers for one timeout after the final ACK of a write, ACKing the final
+/// block again if the client resends it because that ACK was lost. The
+/// timeout runs from that ACK, however much the client resends, and ends no
+/// later than the transfer's deadline.
+fn dally<T: Transport>(t: &Transfer<T>, final_block: u16) -> io::Result<TransferResult> {
+    let until = Instant::now() + ACK_TIMEOUT;
+    let until = t.deadline.map_or(until, |deadline| deadline.min(until));
+
+    while let Ok(Some(packet)) = recv_before(&t.rx, None, Some(until)) {
+        match packet {
+            Packet::Data { block, .. } if block == final_block => {
+                t.socket
+                    .send_to(Packet::new_ack(block).serialize().as_slice(), t.dst)?;
+            }
+            Packet::Error { .. } => break,
+            _ => continue,
+        }
+    }
+
+    Ok(TransferResult::Completed)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use super::{
+        authorize, bind_to_device, check_peer, create_dirs, glob_match, hooked_write_process,
+        is_temp_name, negotiate_blksize, outcome_line, read_process, resolve, root_for, send_file,
+        spawn_worker, timeout, write_process, BufferPool, BusyBehavior, Capability, Config,
+        ConfigError, ConnectionTable, Direction, LogSink, NotFoundBehavior, OpenWrite, PeerSocket,
+        Registry, RegistryEntry, Server, TempFile, Transfer, TransferInfo, TransferResult,
+        Transport, ACK_TIMEOUT, DEFAULT_IO_BUFFER, HEALTH_FILE, INDEX_FILE, LOG_LINE_MAX,
+        LOG_SINK_FILE, MAX_RETRANSMITS,
+    };
+    use crate::metrics::{self, InMemoryMetrics};
+    use crate::packet::{
+        Mode, Packet, ACCESS_VIOLATION, DISK_FULL, FILE_NOT_FOUND, ILLEGAL_OP, MAX_BLKSIZE,
+        OPTION_NEGOTIATION, READ_OPCODE, SEE_MSG, UNKNOWN_TID, WRITE_OPCODE,
+    };
+
+    /// Datagrams sent by a worker, along with their destination
+    type Sent = Receiver<(Vec<u8>, SocketAddr)>;
+
+    /// Hands every sent datagram to the test instead of the network
+    struct MockTransport {
+        sent: Mutex<Sender<(Vec<u8>, SocketAddr)>>,
+    }
+
+    impl Transport for MockTransport {
+        fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+            self.sent
+                .lock()
+                .unwrap()
+                .send((buf.to_vec(), addr))
+                .unwrap();
+            Ok(buf.len())
+        }
+    }
+
+    /// Fails every send the way a socket does once the peer's host has
+    /// answered with an ICMP port-unreachable
+    struct RefusingTransport;
+
+    impl Transport for RefusingTransport {
+        fn send_to(&self, _buf: &[u8], _addr: SocketAddr) -> io::Result<usize> {
+            Err(io::ErrorKind::ConnectionRefused.into())
+        }
+    }
+
+    #[test]
+    fn test_peer_gone() {
+        let path = temp_file("peer-gone", b"hello world");
+        let (_tx, rx) = mpsc::channel();
+        let res = read_process(
+            &transfer(Arc::new(RefusingTransport), rx, Direction::Read),
+            path.clone(),
+            Mode::Octet,
+            DEFAULT_IO_BUFFER,
+        );
+        assert!(matches!(res, TransferResult::PeerGone));
+
+        let (_tx, rx) = mpsc::channel();
+        let res = write_process(
+            &transfer(Arc::new(RefusingTransport), rx, Direction::Write),
+            path.clone(),
+            DEFAULT_IO_BUFFER,
+            None,
+            None,
+        );
+        assert!(matches!(res, TransferResult::PeerGone));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    fn mock_transport() -> (Arc<MockTransport>, Sent) {
+        let (tx, rx) = mpsc::channel();
+        let transport = MockTransport {
+            sent: Mutex::new(tx),
+        };
+
+        (Arc::new(transport), rx)
+    }
+
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("tftp-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    /// Where writes to `path` go until they're complete
+    fn parts_of(path: &Path) -> Vec<PathBuf> {
+        let prefix = format!(".{}.", path.file_name().unwrap().to_str().unwrap());
+
+        fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|part| {
+                let name = part.file_name().unwrap();
+                is_temp_name(name) && name.to_str().unwrap().starts_with(&prefix)
+            })
+            .collect()
+    }
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:6969".parse().unwrap()
+    }
+
+    fn entry_in(registry: &Registry) -> RegistryEntry {
+        registry.register(
+            TransferInfo {
+                id: registry.next_id(),
+                peer: peer(),
+                file: String::new(),
+                direction: Direction::Read,
+                bytes: 0,
+                total: None,
+            },
+            None,
+        )
+    }
+
+    /// A transfer for `peer()` that asked for nothing, with no deadline
+    fn transfer<T>(socket: Arc<T>, rx: Receiver<Packet>, direction: Direction) -> Transfer<T> {
+        Transfer {
+            socket,
+            dst: peer(),
+            rx,
+            options: Vec::new(),
+            deadline: None,
+            pool: Arc::default(),
+            entry: entry(direction),
+        }
+    }
+
+    fn entry(direction: Direction) -> RegistryEntry {
+        Registry::default().register(
+            TransferInfo {
+                id: 0,
+                peer: peer(),
+                file: String::new(),
+                direction,
+                bytes: 0,
+                total: None,
+            },
+            None,
+        )
+    }
+
+    fn config() -> Config {
+        Config {
+            denied_extensions: vec!["*.sh".to_owned()],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_authorize_denied_extension() {
+        match authorize(&config(), WRITE_OPCODE, "scripts/Install.SH") {
+            Err(Packet::Error { code, msg: _ }) => assert_eq!(code, ACCESS_VIOLATION),
+            _ => panic!("expected an access violation"),
+        }
+    }
+
+    #[test]
+    fn test_authorize_allowed_extension() {
+        assert!(authorize(&config(), READ_OPCODE, "images/boot.img").is_ok());
+    }
+
+    #[test]
+    fn test_authorize_read_only() {
+        let config = Config {
+            read_only: true,
+            ..config()
+        };
+
+        assert!(authorize(&config, READ_OPCODE, "boot.img").is_ok());
+        let err = authorize(&config, WRITE_OPCODE, "boot.img").unwrap_err();
+        assert_eq!(err.error_code(), Some(ACCESS_VIOLATION));
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let root = temp_dir("max-connections");
+        fs::write(root.join("big.bin"), [b'x'; 2048]).unwrap();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            max_connections: Some(1),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let first = client();
+        first
+            .send_to(&request(READ_OPCODE, "big.bin", "octet", &[]), addr)
+            .unwrap();
+        assert!(matches!(recv_from(&first), Packet::Data { block: 1, .. }));
+
+        let second = client();
+        second
+            .send_to(&request(READ_OPCODE, "big.bin", "octet", &[]), addr)
+            .unwrap();
+        assert_eq!(recv_from(&second).error_msg(), Some("Too many transfers"));
+
+        first
+            .send_to(&Packet::new_error(SEE_MSG, "done").serialize(), addr)
+            .unwrap();
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_authorize_filename_too_long() {
+        let file = "a".repeat(4096);
+
+        match authorize(&Config::default(), READ_OPCODE, &file) {
+            Err(Packet::Error { code, msg: _ }) => assert_eq!(code, ILLEGAL_OP),
+            _ => panic!("expected an illegal operation"),
+        }
+    }
+
+    #[test]
+    fn test_shutdown_stops_server() {
+        let server = Server::bind("127.0.0.1:0", Config::default()).unwrap();
+        let shutdown = server.shutdown_handle();
+
+        let handle = thread::spawn(move || server.run());
+        shutdown.shutdown();
+
+        handle.join().unwrap().unwrap();
+    }
+
+    #[cfg(all(unix, feature = "signals"))]
+    #[test]
+    fn test_signal_stops_server() {
+        use signal_hook::consts::SIGTERM;
+
+        let server = Server::bind("127.0.0.1:0", Config::default()).unwrap();
+        server.handle_signals().unwrap();
+
+        let handle = thread::spawn(move || server.run());
+        signal_hook::low_level::raise(SIGTERM).unwrap();
+
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_read_process_data_ack_sequence() {
+        let contents: Vec<u8> = (0..700).map(|i| i as u8).collect();
+        let path = temp_file("read-sequence", &contents);
+
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+
+        let file = path.to_path_buf();
+        let worker = thread::spawn(move || {
+            read_process(
+                &transfer(transport, rx, Direction::Read),
+                file,
+                Mode::Octet,
+                DEFAULT_IO_BUFFER,
+            )
+        });
+
+        for (block, chunk) in (1..).zip(contents.chunks(512)) {
+            let (bytes, addr) = sent.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(addr, peer());
+
+            match Packet::deserialize(&bytes).unwrap() {
+                Packet::Data {
+                    block: got,
+                    data,
+                    len,
+                } => {
+                    assert_eq!(got, block);
+                    assert_eq!(len, chunk.len());
+                    assert_eq!(data, chunk);
+                }
+                _ => panic!("did not get expected packet: Data"),
+            }
+
+            tx.send(Packet::new_ack(block)).unwrap();
+        }
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+        assert!(sent.try_recv().is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Remembers the largest buffer it was ever asked to fill
+    struct PeakReader<R> {
+        inner: R,
+        peak: Arc<AtomicUsize>,
+    }
+
+    impl<R: Read> Read for PeakReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.peak.fetch_max(buf.len(), Ordering::Relaxed);
+            self.inner.read(buf)
+        }
+    }
+
+    /// Counts the calls made to `read`
+    struct CountingReader<R> {
+        inner: R,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.inner.read(buf)
+        }
+    }
+
+    /// Runs `transfers` small RRQs one after another, returning how many
+    /// buffers it took a pool keeping `max` to serve them
+    fn pooled_allocations(max: usize, transfers: usize) -> usize {
+        let pool = Arc::new(BufferPool::new(max));
+        for _ in 0..transfers {
+            let (transport, sent) = mock_transport();
+            let (tx, rx) = mpsc::channel();
+            let worker_pool = pool.clone();
+            let worker = thread::spawn(move || {
+                send_file(
+                    &Transfer {
+                        pool: worker_pool,
+                        ..transfer(transport, rx, Direction::Read)
+                    },
+                    io::Cursor::new(vec![b'x'; 600]),
+                    512,
+                    ACK_TIMEOUT,
+                )
+                .unwrap()
+            });
+
+            for block in 1..=2 {
+                assert!(matches!(recv_packet(&sent), Packet::Data { .. }));
+                tx.send(Packet::new_ack(block)).unwrap();
+            }
+            assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+        }
+
+        pool.allocated.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        // Each transfer takes a buffer for its block and one for the packet
+        assert_eq!(pooled_allocations(0, 10), 20);
+        assert_eq!(pooled_allocations(2, 10), 2);
+    }
+
+    #[test]
+    fn test_send_file_retransmits_cached_block() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let reader = CountingReader {
+            inner: io::Cursor::new(vec![b'x'; 600]),
+            reads: reads.clone(),
+        };
+
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            send_file(
+                &transfer(transport, rx, Direction::Read),
+                reader,
+                512,
+                ACK_TIMEOUT,
+            )
+        });
+
+        let (original, _) = sent.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(reads.load(Ordering::Relaxed), 1);
+
+        // No ACK, so the same block goes out again from the cache
+        let (retransmitted, _) = sent.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(retransmitted, original);
+        assert_eq!(reads.load(Ordering::Relaxed), 1);
+
+        tx.send(Packet::new_ack(1)).unwrap();
+        assert!(matches!(recv_packet(&sent), Packet::Data { block: 2, .. }));
+        tx.send(Packet::new_ack(2)).unwrap();
+
+        assert!(matches!(
+            worker.join().unwrap().unwrap(),
+            TransferResult::Completed
+        ));
+        // The short final block takes one more read to find the end
+        assert_eq!(reads.load(Ordering::Relaxed), 3);
+    }
+
+    fn spawn_send_file(timeout: Duration) -> (Sent, Sender<Packet>, JoinHandle<TransferResult>) {
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            send_file(
+                &transfer(transport, rx, Direction::Read),
+                io::Cursor::new(vec![b'x'; 600]),
+                512,
+                timeout,
+            )
+            .unwrap()
+        });
+
+        (sent, tx, worker)
+    }
+
+    #[test]
+    fn test_send_file_aborted() {
+        let (sent, tx, worker) = spawn_send_file(ACK_TIMEOUT);
+        assert!(matches!(recv_packet(&sent), Packet::Data { block: 1, .. }));
+
+        tx.send(Packet::new_error(ACCESS_VIOLATION, "Go away"))
+            .unwrap();
+        match worker.join().unwrap() {
+            TransferResult::Aborted { code, msg } => {
+                assert_eq!(code, ACCESS_VIOLATION);
+                assert_eq!(msg, "Go away");
+            }
+            res => panic!("expected Aborted, got {:?}", res),
+        }
+    }
+
+    /// Hands out at most `chunk` bytes per `read`, like a pipe would
+    struct Trickle<R> {
+        inner: R,
+        chunk: usize,
+    }
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.chunk);
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_send_file_short_reads() {
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            let reader = Trickle {
+                inner: io::Cursor::new(vec![b'x'; 600]),
+                chunk: 100,
+            };
+            send_file(
+                &transfer(transport, rx, Direction::Read),
+                reader,
+                512,
+                ACK_TIMEOUT,
+            )
+            .unwrap()
+        });
+
+        for (exp_block, exp_len) in [(1, 512), (2, 88)] {
+            match recv_packet(&sent) {
+                Packet::Data { block, data, .. } => {
+                    assert_eq!(block, exp_block);
+                    assert_eq!(data.len(), exp_len);
+                }
+                _ => panic!("did not get expected packet: Data"),
+            }
+            tx.send(Packet::new_ack(exp_block)).unwrap();
+        }
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+    }
+
+    /// The `read`s the calling thread has made so far, as the kernel counts
+    /// them
+    #[cfg(target_os = "linux")]
+    fn read_syscalls() -> usize {
+        let io = fs::read_to_string("/proc/thread-self/io").unwrap();
+        io.lines()
+            .find_map(|line| line.strip_prefix("syscr: "))
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    /// Reads it takes `read_process` to serve a 64 KiB file with an
+    /// `io_buffer_size` of `io_buffer`
+    #[cfg(target_os = "linux")]
+    fn reads_serving(io_buffer: usize) -> usize {
+        let path = temp_file(&format!("io-buffer-{}", io_buffer), &vec![b'x'; 64 * 1024]);
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+        let file = path.clone();
+        let worker = thread::spawn(move || {
+            let before = read_syscalls();
+            let res = read_process(
+                &transfer(transport, rx, Direction::Read),
+                file,
+                Mode::Octet,
+                io_buffer,
+            );
+            (res, read_syscalls() - before)
+        });
+
+        // 128 full blocks and the empty one after them
+        for block in 1..=129 {
+            assert!(matches!(recv_packet(&sent), Packet::Data { .. }));
+            tx.send(Packet::new_ack(block)).unwrap();
+        }
+        let (res, reads) = worker.join().unwrap();
+        assert!(matches!(res, TransferResult::Completed));
+
+        fs::remove_file(path).unwrap();
+        reads
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_io_buffer_coalesces_reads() {
+        // A block at a time with a buffer no bigger than one
+        assert!(reads_serving(512) >= 128);
+        // The default buffer holds 16 blocks. Besides its 8 fills and the
+        // one that finds the end, a few reads are counting them.
+        assert!(reads_serving(DEFAULT_IO_BUFFER) <= 16);
+    }
+
+    #[test]
+    fn test_send_file_timed_out() {
+        let (sent, _tx, worker) = spawn_send_file(Duration::from_millis(10));
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::TimedOut));
+        // The block itself, then every retransmit
+        assert_eq!(sent.try_iter().count(), MAX_RETRANSMITS as usize + 1);
+    }
+
+    #[test]
+    fn test_oack_timed_out() {
+        let path = temp_file("oack-timed-out", b"hello");
+        let (transport, sent) = mock_transport();
+        let (_tx, rx) = mpsc::channel();
+        let file = path.clone();
+        let worker = thread::spawn(move || {
+            read_process(
+                &Transfer {
+                    options: vec![("utimeout".to_owned(), "10000".to_owned())],
+                    ..transfer(transport, rx, Direction::Read)
+                },
+                file,
+                Mode::Octet,
+                DEFAULT_IO_BUFFER,
+            )
+        });
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::TimedOut));
+        // The OACK itself, then every retransmit
+        let oacks = sent.try_iter().collect::<Vec<_>>();
+        assert_eq!(oacks.len(), MAX_RETRANSMITS as usize + 1);
+        assert!(oacks
+            .iter()
+            .all(|(bytes, _)| matches!(Packet::deserialize(bytes), Ok(Packet::OAck { .. }))));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_send_file_streams_large_sparse_file() {
+        // Large enough for the block number to roll over
+        const SIZE: u64 = 40 << 20;
+
+        let path = temp_file("sparse", &[]);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(SIZE)
+            .unwrap();
+
+        let peak = Arc::new(AtomicUsize::new(0));
+        let reader = PeakReader {
+            inner: fs::File::open(&path).unwrap(),
+            peak: peak.clone(),
+        };
+
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            send_file(
+                &transfer(transport, rx, Direction::Read),
+                reader,
+                512,
+                ACK_TIMEOUT,
+            )
+        });
+
+        let mut total = 0;
+        loop {
+            let (bytes, _) = sent.recv_timeout(Duration::from_secs(5)).unwrap();
+
+            let (block, len) = match Packet::deserialize(&bytes).unwrap() {
+                Packet::Data { block, len, .. } => (block, len),
+                _ => panic!("did not get expected packet: Data"),
+            };
+
+            total += len as u64;
+            tx.send(Packet::new_ack(block)).unwrap();
+
+            if len < 512 {
+                break;
+            }
+        }
+
+        assert!(matches!(
+            worker.join().unwrap().unwrap(),
+            TransferResult::Completed
+        ));
+        assert_eq!(total, SIZE);
+        assert_eq!(peak.load(Ordering::Relaxed), 512);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Reads a single-block file through `read_process` in the given mode
+    fn read_single_block(name: &str, contents: &[u8], mode: Mode) -> Vec<u8> {
+        let path = temp_file(name, contents);
+
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+
+        let file = path.to_path_buf();
+        let worker = thread::spawn(move || {
+            read_process(
+                &transfer(transport, rx, Direction::Read),
+                file,
+                mode,
+                DEFAULT_IO_BUFFER,
+            )
+        });
+
+        let (bytes, _) = sent.recv_timeout(Duration::from_secs(5)).unwrap();
+        tx.send(Packet::new_ack(1)).unwrap();
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+
+        fs::remove_file(path).unwrap();
+
+        match Packet::deserialize(&bytes).unwrap() {
+            Packet::Data { data, .. } => data,
+            _ => panic!("did not get expected packet: Data"),
+        }
+    }
+
+    #[test]
+    fn test_progress_events() {
+        let path = temp_file("progress", &[b'x'; 1100]);
+        let (progress_tx, progress) = mpsc::channel();
+        let entry = Registry::default().register(
+            TransferInfo {
+                id: 0,
+                peer: peer(),
+                file: "name.txt".to_owned(),
+                direction: Direction::Read,
+                bytes: 0,
+                total: None,
+            },
+            Some(progress_tx),
+        );
+
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+        let file = path.clone();
+        let worker = thread::spawn(move || {
+            read_process(
+                &Transfer {
+                    entry,
+                    ..transfer(transport, rx, Direction::Read)
+                },
+                file,
+                Mode::Octet,
+                DEFAULT_IO_BUFFER,
+            )
+        });
+
+        for block in 1..=3 {
+            assert!(matches!(recv_packet(&sent), Packet::Data { .. }));
+            tx.send(Packet::new_ack(block)).unwrap();
+        }
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+
+        let events: Vec<_> = progress.try_iter().collect();
+        let bytes: Vec<_> = events.iter().map(|event| event.bytes_so_far).collect();
+        assert_eq!(bytes, [512, 1024, 1100]);
+        for event in events {
+            assert_eq!(event.peer, peer());
+            assert_eq!(event.file, "name.txt");
+            assert_eq!(event.total, Some(1100));
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap_process() {
+        use super::mmap_process;
+
+        let contents: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+        let path = temp_file("mmap", &contents);
+
+        let download = |mmap: bool| {
+            let (transport, sent) = mock_transport();
+            let (tx, rx) = mpsc::channel();
+            let file = path.clone();
+            let worker = thread::spawn(move || {
+                let process = if mmap { mmap_process } else { read_process };
+                process(
+                    &transfer(transport, rx, Direction::Read),
+                    file,
+                    Mode::Octet,
+                    DEFAULT_IO_BUFFER,
+                )
+            });
+
+            let mut received = Vec::new();
+            loop {
+                let (block, data) = match recv_packet(&sent) {
+                    Packet::Data { block, data, .. } => (block, data),
+                    _ => panic!("did not get expected packet: Data"),
+                };
+                received.extend_from_slice(&data);
+                tx.send(Packet::new_ack(block)).unwrap();
+                if data.len() < 512 {
+                    break;
+                }
+            }
+            assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+
+            received
+        };
+
+        let mapped = download(true);
+        assert_eq!(mapped, contents);
+        assert_eq!(mapped, download(false));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_exactly_one_block() {
+        let path = temp_file("one-block", &[b'x'; 512]);
+        let (sent, tx, worker) = spawn_read(&path, Vec::new());
+
+        match recv_packet(&sent) {
+            Packet::Data { block, data, .. } => {
+                assert_eq!(block, 1);
+                assert_eq!(data.len(), 512);
+            }
+            _ => panic!("did not get expected packet: Data"),
+        }
+        tx.send(Packet::new_ack(1)).unwrap();
+
+        // A full block can't end the transfer, so an empty one follows
+        match recv_packet(&sent) {
+            Packet::Data { block, data, .. } => {
+                assert_eq!(block, 2);
+                assert!(data.is_empty());
+            }
+            _ => panic!("did not get expected packet: Data"),
+        }
+        tx.send(Packet::new_ack(2)).unwrap();
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+        assert!(sent.try_recv().is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_process_octet_vs_netascii() {
+        let contents = b"line one\nline two\r\n";
+
+        let octet = read_single_block("octet", contents, Mode::Octet);
+        assert_eq!(octet, contents);
+
+        let netascii = read_single_block("netascii", contents, Mode::NetAscii);
+        assert_eq!(netascii, b"line one\r\nline two\r\0\r\n");
+    }
+
+    fn expect_ack(sent: &Sent, exp_block: u16) {
+        let (bytes, _) = sent.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        match Packet::deserialize(&bytes).unwrap() {
+            Packet::Ack { block } => assert_eq!(block, exp_block),
+            _ => panic!("did not get expected packet: Ack"),
+        }
+    }
+
+    /// Uploads one full block followed by a final block of `final_len` bytes
+    fn test_write_final_block(name: &str, final_len: usize) {
+        const BLKSIZE: usize = 512;
+
+        let path = temp_file(name, &[]);
+
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+
+        let file = path.to_path_buf();
+        let worker = thread::spawn(move || {
+            write_process(
+                &transfer(transport, rx, Direction::Write),
+                file,
+                DEFAULT_IO_BUFFER,
+                None,
+                None,
+            )
+        });
+        expect_ack(&sent, 0);
+
+        tx.send(Packet::new_data(1, vec![b'a'; BLKSIZE], BLKSIZE))
+            .unwrap();
+        expect_ack(&sent, 1);
+
+        tx.send(Packet::new_data(2, vec![b'b'; final_len], final_len))
+            .unwrap();
+        expect_ack(&sent, 2);
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+
+        let written = fs::read(&path).unwrap();
+        assert_eq!(written.len(), BLKSIZE + final_len);
+        assert!(written[BLKSIZE..].iter().all(|&b| b == b'b'));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_max_blocks() {
+        let path = temp_file("max-blocks", &[]);
+
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+
+        let file = path.to_path_buf();
+        let worker = thread::spawn(move || {
+            write_process(
+                &transfer(transport, rx, Direction::Write),
+                file,
+                DEFAULT_IO_BUFFER,
+                Some(2),
+                None,
+            )
+        });
+        expect_ack(&sent, 0);
+
+        for block in 1..=2 {
+            tx.send(Packet::new_data(block, vec![b'a'; 512], 512))
+                .unwrap();
+            expect_ack(&sent, block);
+        }
+
+        tx.send(Packet::new_data(3, vec![b'a'; 512], 512)).unwrap();
+        assert_eq!(recv_packet(&sent).error_code(), Some(DISK_FULL));
+
+        assert!(matches!(
+            worker.join().unwrap(),
+            TransferResult::Rejected {
+                code: DISK_FULL,
+                ..
+            }
+        ));
+        // The aborted write leaves the file as it was
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_unexpected_packet() {
+        let oack = || Packet::OAck {
+            options: vec![("blksize".to_owned(), "1024".to_owned())],
+        };
+        let rejected = |res| {
+            matches!(
+                res,
+                TransferResult::Rejected {
+                    code: ILLEGAL_OP,
+                    ..
+                }
+            )
+        };
+
+        let path = temp_file("unexpected-read", &[b'x'; 100]);
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+        let file = path.clone();
+        let worker = thread::spawn(move || {
+            read_process(
+                &transfer(transport, rx, Direction::Read),
+                file,
+                Mode::Octet,
+                DEFAULT_IO_BUFFER,
+            )
+        });
+        assert!(matches!(recv_packet(&sent), Packet::Data { .. }));
+        tx.send(oack()).unwrap();
+        assert_eq!(recv_packet(&sent).error_code(), Some(ILLEGAL_OP));
+        assert!(rejected(worker.join().unwrap()));
+        fs::remove_file(path).unwrap();
+
+        let path = temp_file("unexpected-write", &[]);
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+        let file = path.clone();
+        let worker = thread::spawn(move || {
+            write_process(
+                &transfer(transport, rx, Direction::Write),
+                file,
+                DEFAULT_IO_BUFFER,
+                None,
+                None,
+            )
+        });
+        expect_ack(&sent, 0);
+        tx.send(oack()).unwrap();
+        assert_eq!(recv_packet(&sent).error_code(), Some(ILLEGAL_OP));
+        assert!(rejected(worker.join().unwrap()));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_temp_file() {
+        let path = temp_file("temp-file", b"old");
+
+        let (temp, mut file) = TempFile::create(path.clone(), 1).unwrap();
+        file.write_all(b"new").unwrap();
+        drop(file);
+        assert_eq!(parts_of(&path), vec![temp.path.clone()]);
+        assert_eq!(fs::read(&temp.path).unwrap(), b"new");
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+
+        temp.commit().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert!(parts_of(&path).is_empty());
+
+        // A worker that panics mid-write unwinds through the guard
+        let dest = path.clone();
+        let res = thread::spawn(move || {
+            let (_temp, mut file) = TempFile::create(dest, 2).unwrap();
+            file.write_all(b"half").unwrap();
+            panic!("worker died");
+        })
+        .join();
+        assert!(res.is_err());
+        assert!(parts_of(&path).is_empty());
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_temp_file_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_file("temp-file-mode", b"old");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let (temp, _file) = TempFile::create(path.clone(), 1).unwrap();
+        temp.commit().unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_beside_part_file() {
+        let path = temp_file("beside-part", b"old");
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let part = path.with_file_name(format!(".{}.part", name));
+        fs::write(&part, b"someone else's").unwrap();
+
+        let (sent, tx, worker) = spawn_write(&path, Vec::new());
+        expect_ack(&sent, 0);
+        tx.send(Packet::new_data(1, b"new".to_vec(), 3)).unwrap();
+        expect_ack(&sent, 1);
+        drop(tx);
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        // A file that only looks like a temp file is neither truncated nor
+        // renamed into place
+        assert_eq!(fs::read(&part).unwrap(), b"someone else's");
+
+        fs::remove_file(part).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    /// A writer on a disk with no room left
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::StorageFull.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_fails_midway() {
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+        let open: Box<OpenWrite> = Box::new(|_| Ok(Box::new(FullDisk)));
+        let worker = thread::spawn(move || {
+            hooked_write_process(
+                &transfer(transport, rx, Direction::Write),
+                &*open,
+                "full.bin",
+                None,
+            )
+        });
+        expect_ack(&sent, 0);
+
+        // The client hears why rather than resending into silence
+        tx.send(Packet::new_data(1, b"data".to_vec(), 4)).unwrap();
+        assert_eq!(recv_packet(&sent).error_code(), Some(DISK_FULL));
+
+        match worker.join().unwrap() {
+            TransferResult::Failed(e) => assert_eq!(e.kind(), io::ErrorKind::StorageFull),
+            _ => panic!("expected the transfer to fail"),
+        }
+        assert!(sent.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_dally_ends_while_final_block_resent() {
+        let path = temp_file("dally-resent", b"");
+
+        let (sent, tx, worker) = spawn_write(&path, Vec::new());
+        expect_ack(&sent, 0);
+
+        // The client never hears the final ACK and keeps resending its block
+        let start = Instant::now();
+        while !worker.is_finished() {
+            assert!(start.elapsed() < ACK_TIMEOUT * 3, "still dallying");
+            let _ = tx.send(Packet::new_data(1, b"end".to_vec(), 3));
+            thread::sleep(ACK_TIMEOUT / 4);
+        }
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+        assert_eq!(fs::read(&path).unwrap(), b"end");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_registry_entry_removed_on_panic() {
+        let registry = Registry::default();
+
+        let entry = entry_in(&registry);
+        assert_eq!(registry.list().len(), 1);
+        drop(entry);
+        assert!(registry.list().is_empty());
+
+        let worker = registry.clone();
+        let res = thread::spawn(move || {
+            let _entry = entry_in(&worker);
+            panic!("worker died");
+        })
+        .join();
+        assert!(res.is_err());
+        assert!(registry.list().is_empty());
+    }
+
+    #[test]
+    fn test_write_duplicate_block() {
+        let path = temp_file("write-duplicate", &[]);
+
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+
+        // Queue the whole burst before the worker gets to it
+        tx.send(Packet::new_data(1, vec![b'a'; 512], 512)).unwrap();
+        tx.send(Packet::new_data(1, vec![b'a'; 512], 512)).unwrap();
+        tx.send(Packet::new_data(3, vec![b'c'; 3], 3)).unwrap();
+        tx.send(Packet::new_data(2, vec![b'b'; 3], 3)).unwrap();
+
+        let file = path.to_path_buf();
+        let worker = thread::spawn(move || {
+            write_process(
+                &transfer(transport, rx, Direction::Write),
+                file,
+                DEFAULT_IO_BUFFER,
+                None,
+                None,
+            )
+        });
+        expect_ack(&sent, 0);
+        expect_ack(&sent, 1);
+        expect_ack(&sent, 1);
+        expect_ack(&sent, 2);
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+        assert!(sent.try_recv().is_err());
+
+        let mut expected = vec![b'a'; 512];
+        expected.extend_from_slice(b"bbb");
+        assert_eq!(fs::read(&path).unwrap(), expected);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_dallies_after_final_block() {
+        let path = temp_file("write-dally", &[]);
+
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+
+        let file = path.to_path_buf();
+        let worker = thread::spawn(move || {
+            write_process(
+                &transfer(transport, rx, Direction::Write),
+                file,
+                DEFAULT_IO_BUFFER,
+                None,
+                None,
+            )
+        });
+        expect_ack(&sent, 0);
+
+        tx.send(Packet::new_data(1, b"short".to_vec(), 5)).unwrap();
+        expect_ack(&sent, 1);
+        assert_eq!(fs::read(&path).unwrap(), b"short");
+
+        // The final ACK was lost, so the client sends the block again
+        tx.send(Packet::new_data(1, b"short".to_vec(), 5)).unwrap();
+        expect_ack(&sent, 1);
+
+        // Once a timeout passes without a resend, the worker exits
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+        assert!(sent.try_recv().is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"short");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_timed_out() {
+        let path = temp_file("write-stall", b"before");
+
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+        let file = path.to_path_buf();
+        let worker = thread::spawn(move || {
+            write_process(
+                &Transfer {
+                    options: vec![("utimeout".to_owned(), "10000".to_owned())],
+                    ..transfer(transport, rx, Direction::Write)
+                },
+                file,
+                DEFAULT_IO_BUFFER,
+                None,
+                None,
+            )
+        });
+        assert!(matches!(recv_packet(&sent), Packet::OAck { .. }));
+
+        // The client stalls after its first block
+        tx.send(Packet::new_data(1, vec![b'a'; 512], 512)).unwrap();
+        expect_ack(&sent, 1);
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::TimedOut));
+        // That ACK again on every timeout
+        for _ in 0..MAX_RETRANSMITS {
+            expect_ack(&sent, 1);
+        }
+        assert!(sent.try_recv().is_err());
+        // The partial upload is thrown away
+        assert_eq!(fs::read(&path).unwrap(), b"before");
+        assert!(parts_of(&path).is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    fn spawn_write(
+        path: &Path,
+        options: Vec<(String, String)>,
+    ) -> (Sent, Sender<Packet>, JoinHandle<TransferResult>) {
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+
+        let file = path.to_path_buf();
+        let worker = thread::spawn(move || {
+            write_process(
+                &Transfer {
+                    options,
+                    ..transfer(transport, rx, Direction::Write)
+                },
+                file,
+                DEFAULT_IO_BUFFER,
+                None,
+                None,
+            )
+        });
+
+        (sent, tx, worker)
+    }
+
+    #[test]
+    fn test_write_window_with_gap() {
+        let path = temp_file("write-window", &[]);
+
+        let options = vec![("windowsize".to_owned(), "4".to_owned())];
+        let (sent, tx, worker) = spawn_write(&path, options);
+        match recv_packet(&sent) {
+            Packet::OAck { options } => {
+                assert_eq!(options, [("windowsize".to_owned(), "4".to_owned())]);
+            }
+            _ => panic!("did not get expected packet: OAck"),
+        }
+
+        let block = |n: u16, len: usize| Packet::new_data(n, vec![b'a' + n as u8; len], len);
+
+        // Block 3 of the first window is lost, so the client is told to
+        // resume after 2, and 4 isn't written ahead of it
+        for n in [1, 2, 4] {
+            tx.send(block(n, 512)).unwrap();
+        }
+        expect_ack(&sent, 2);
+
+        // The resent window is ACKed once, at its end
+        for n in 3..=6 {
+            tx.send(block(n, 512)).unwrap();
+        }
+        expect_ack(&sent, 6);
+
+        // The final block ends the file mid-window
+        tx.send(block(7, 10)).unwrap();
+        expect_ack(&sent, 7);
+        drop(tx);
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+        assert!(sent.try_recv().is_err());
+
+        let written = fs::read(&path).unwrap();
+        assert_eq!(written.len(), 6 * 512 + 10);
+        for (i, chunk) in written.chunks(512).enumerate() {
+            assert!(chunk.iter().all(|&b| b == b'a' + i as u8 + 1));
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_over_tsize() {
+        let path = temp_file("over-tsize", &[]);
+
+        let options = vec![("tsize".to_owned(), "600".to_owned())];
+        let (sent, tx, worker) = spawn_write(&path, options);
+        assert!(matches!(recv_packet(&sent), Packet::OAck { .. }));
+
+        tx.send(Packet::new_data(1, vec![b'a'; 512], 512)).unwrap();
+        expect_ack(&sent, 1);
+
+        tx.send(Packet::new_data(2, vec![b'b'; 100], 100)).unwrap();
+        assert_eq!(recv_packet(&sent).error_code(), Some(ILLEGAL_OP));
+
+        assert!(matches!(
+            worker.join().unwrap(),
+            TransferResult::Rejected {
+                code: ILLEGAL_OP,
+                ..
+            }
+        ));
+        // The aborted write leaves the file as it was, and nothing beside it
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        assert!(parts_of(&path).is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_under_tsize() {
+        let path = temp_file("under-tsize", &[]);
+
+        let options = vec![("tsize".to_owned(), "600".to_owned())];
+        let (sent, tx, worker) = spawn_write(&path, options);
+        assert!(matches!(recv_packet(&sent), Packet::OAck { .. }));
+
+        // Falling short is only worth a warning, the file is kept
+        tx.send(Packet::new_data(1, vec![b'a'; 100], 100)).unwrap();
+        expect_ack(&sent, 1);
+        drop(tx);
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+        assert_eq!(fs::metadata(&path).unwrap().len(), 100);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_final_block_empty() {
+        test_write_final_block("final-empty", 0);
+    }
+
+    #[test]
+    fn test_write_final_block_one_byte() {
+        test_write_final_block("final-one", 1);
+    }
+
+    #[test]
+    fn test_write_final_block_blksize_minus_one() {
+        test_write_final_block("final-511", 511);
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("tftp-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&path).unwrap();
+
+        path
+    }
+
+    fn start_server(config: Config) -> (Arc<Server>, JoinHandle<io::Result<()>>) {
+        let server = Arc::new(Server::bind("127.0.0.1:0", config).unwrap());
+
+        let running = server.clone();
+        let handle = thread::spawn(move || running.run());
+
+        (server, handle)
+    }
+
+    fn stop_server(server: &Server, handle: JoinHandle<io::Result<()>>) {
+        server.shutdown_handle().shutdown();
+        handle.join().unwrap().unwrap();
+    }
+
+    fn client() -> UdpSocket {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        client
+    }
+
+    /// A RRQ/WRQ with the given options, built by hand so tests don't depend
+    /// on the serializer
+    fn request(op_code: u16, file: &str, mode: &str, options: &[(&str, &str)]) -> Vec<u8> {
+        let mut bytes = op_code.to_be_bytes().to_vec();
+
+        for field in [file, mode] {
+            bytes.extend_from_slice(field.as_bytes());
+            bytes.push(0);
+        }
+        for (name, value) in options {
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.push(0);
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+
+        bytes
+    }
+
+    fn recv_from(client: &UdpSocket) -> Packet {
+        let mut buf = vec![0; MAX_BLKSIZE + 4];
+        let (n, _) = client.recv_from(&mut buf).unwrap();
+
+        Packet::deserialize(&buf[..n]).unwrap()
+    }
+
+    /// Downloads `file` in octet mode, ACKing every block
+    fn download(client: &UdpSocket, addr: SocketAddr, file: &str) -> Vec<u8> {
+        client
+            .send_to(&request(READ_OPCODE, file, "octet", &[]), addr)
+            .unwrap();
+
+        let mut contents = Vec::new();
+        loop {
+            match recv_from(client) {
+                Packet::Data { block, data, len } => {
+                    contents.extend_from_slice(&data);
+                    client
+                        .send_to(&Packet::new_ack(block).serialize(), addr)
+                        .unwrap();
+
+                    if len < 512 {
+                        return contents;
+                    }
+                }
+                Packet::Error { code, msg } => panic!("Error {}: {}", code, msg),
+                _ => panic!("did not get expected packet: Data"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_active_transfers() {
+        let root = temp_dir("active");
+        fs::write(root.join("active.bin"), [b'x'; 1000]).unwrap();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let client = client();
+        client
+            .send_to(&request(READ_OPCODE, "active.bin", "octet", &[]), addr)
+            .unwrap();
+
+        // The first block is out, so the transfer is waiting on our ACK
+        recv_from(&client);
+
+        let transfers = server.active_transfers();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].peer, client.local_addr().unwrap());
+        assert_eq!(transfers[0].file, "active.bin");
+        assert_eq!(transfers[0].direction, Direction::Read);
+        assert_eq!(transfers[0].bytes, 512);
+
+        client
+            .send_to(&Packet::new_ack(1).serialize(), addr)
+            .unwrap();
+        recv_from(&client);
+        client
+            .send_to(&Packet::new_ack(2).serialize(), addr)
+            .unwrap();
+
+        stop_server(&server, handle);
+        assert!(server.active_transfers().is_empty());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_metrics() {
+        let root = temp_dir("metrics");
+        fs::write(root.join("name.txt"), [b'x'; 1000]).unwrap();
+        let metrics = Arc::new(InMemoryMetrics::default());
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            metrics: Some(metrics.clone()),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        assert_eq!(download(&client(), addr, "name.txt").len(), 1000);
+        stop_server(&server, handle);
+
+        assert_eq!(metrics.get(metrics::TRANSFERS_STARTED), 1);
+        assert_eq!(metrics.get(metrics::TRANSFERS_COMPLETED), 1);
+        assert_eq!(metrics.get(metrics::TRANSFERS_FAILED), 0);
+        assert_eq!(metrics.get(metrics::BYTES_SENT), 1000);
+        assert_eq!(metrics.get(metrics::ACTIVE_TRANSFERS), 0);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_builder() {
+        let root = temp_dir("builder");
+        let build = |config: Config| {
+            Server::builder()
+                .config(Config {
+                    root: root.clone(),
+                    ..config
+                })
+                .bind("127.0.0.1:0")
+                .build()
+        };
+
+        assert!(build(Config::default()).is_ok());
+        assert!(matches!(
+            build(Config {
+                blksize: 4,
+                ..Config::default()
+            }),
+            Err(ConfigError::BlksizeOutOfRange(4))
+        ));
+        assert!(matches!(
+            build(Config {
+                blksize_overrides: vec![("*.img".to_owned(), 70000)],
+                ..Config::default()
+            }),
+            Err(ConfigError::BlksizeOutOfRange(70000))
+        ));
+        assert!(matches!(
+            build(Config {
+                max_filename_len: 0,
+                ..Config::default()
+            }),
+            Err(ConfigError::ZeroFilenameLen)
+        ));
+        assert!(matches!(
+            build(Config {
+                max_blocks: Some(0),
+                ..Config::default()
+            }),
+            Err(ConfigError::ZeroMaxBlocks)
+        ));
+        assert!(matches!(
+            build(Config {
+                mode_subdirs: vec![(Mode::Octet, PathBuf::from("../bin"))],
+                ..Config::default()
+            }),
+            Err(ConfigError::BadModeSubdir(_))
+        ));
+        assert!(matches!(
+            build(Config {
+                chroot: true,
+                subnet_roots: vec![("10.0.0.0/8".parse().unwrap(), root.clone())],
+                ..Config::default()
+            }),
+            Err(ConfigError::SubnetRootsWithChroot)
+        ));
+
+        let missing = Server::builder()
+            .config(Config {
+                root: root.join("missing"),
+                ..Config::default()
+            })
+            .bind("127.0.0.1:0")
+            .build();
+        assert!(matches!(missing, Err(ConfigError::RootNotDirectory(_))));
+
+        let unbound = Server::builder()
+            .config(Config {
+                root: root.clone(),
+                ..Config::default()
+            })
+            .build();
+        assert!(matches!(unbound, Err(ConfigError::NoAddress)));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_peer_capabilities() {
+        let root = temp_dir("peer_capabilities");
+        fs::write(root.join("small.txt"), b"hello").unwrap();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            peer_capabilities: vec![
+                ("10.0.0.0/8".parse().unwrap(), Capability::WriteOnly),
+                ("127.0.0.0/8".parse().unwrap(), Capability::ReadOnly),
+            ],
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        assert_eq!(download(&client, addr, "small.txt"), b"hello");
+
+        let wrq = request(WRITE_OPCODE, "up.txt", "octet", &[]);
+        client.send_to(&wrq, addr).unwrap();
+        assert_eq!(recv_from(&client).error_code(), Some(ACCESS_VIOLATION));
+        assert!(!root.join("up.txt").exists());
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_temp_file_not_requestable() {
+        let root = temp_dir("temp_not_requestable");
+        fs::write(root.join("up.txt"), b"old").unwrap();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        // An upload halfway through
+        let writer = client();
+        let wrq = request(WRITE_OPCODE, "up.txt", "octet", &[]);
+        writer.send_to(&wrq, addr).unwrap();
+        assert!(matches!(recv_from(&writer), Packet::Ack { block: 0 }));
+        let data = Packet::new_data(1, vec![b'a'; 512], 512);
+        writer.send_to(&data.serialize(), addr).unwrap();
+        assert!(matches!(recv_from(&writer), Packet::Ack { block: 1 }));
+
+        let parts = parts_of(&root.join("up.txt"));
+        assert_eq!(parts.len(), 1);
+        let name = parts[0].file_name().unwrap().to_str().unwrap();
+
+        // Its temp file can be neither read nor written by name
+        let client = client();
+        for op_code in [READ_OPCODE, WRITE_OPCODE] {
+            client
+                .send_to(&request(op_code, name, "octet", &[]), addr)
+                .unwrap();
+            assert_eq!(recv_from(&client).error_code(), Some(ACCESS_VIOLATION));
+        }
+
+        let data = Packet::new_data(2, b"b".to_vec(), 1);
+        writer.send_to(&data.serialize(), addr).unwrap();
+        assert!(matches!(recv_from(&writer), Packet::Ack { block: 2 }));
+        assert_eq!(fs::metadata(root.join("up.txt")).unwrap().len(), 513);
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_root_is_file() {
+        let file = temp_file("root_is_file", b"not a directory");
+
+        let res = Server::builder()
+            .config(Config {
+                root: file.clone(),
+                ..Config::default()
+            })
+            .bind("127.0.0.1:0")
+            .build();
+        match res {
+            Err(err @ ConfigError::RootNotDirectory(_)) => {
+                assert_eq!(
+                    err.to_string(),
+                    format!("root {} is not a directory", file.display())
+                );
+            }
+            _ => panic!("expected RootNotDirectory"),
+        }
+
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_from_socket() {
+        let root = temp_dir("from-socket");
+        fs::write(root.join("name.txt"), b"pre-bound").unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let config = Config {
+            root: root.clone(),
+            ..Config::default()
+        };
+        let server = Arc::new(Server::from_socket(socket, config).unwrap());
+        assert_eq!(server.local_addr().unwrap(), addr);
+
+        let running = server.clone();
+        let handle = thread::spawn(move || running.run());
+
+        assert_eq!(download(&client(), addr, "name.txt"), b"pre-bound");
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_injected_tid() {
+        let root = temp_dir("injected-tid");
+        fs::write(root.join("tid.bin"), [b'x'; 1000]).unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tid = socket.local_addr().unwrap();
+        let server = Arc::new(
+            Server::builder()
+                .socket(socket)
+                .config(Config {
+                    root: root.clone(),
+                    ..Config::default()
+                })
+                .build()
+                .unwrap(),
+        );
+        let running = server.clone();
+        let handle = thread::spawn(move || running.run());
+
+        // Every packet of the transfer comes from the port we injected
+        let client = client();
+        client
+            .send_to(&request(READ_OPCODE, "tid.bin", "octet", &[]), tid)
+            .unwrap();
+        let mut buf = vec![0; MAX_BLKSIZE + 4];
+        for exp_block in 1..=2 {
+            let (n, src) = client.recv_from(&mut buf).unwrap();
+            assert_eq!(src, tid);
+            assert_eq!(
+                Packet::deserialize(&buf[..n]).unwrap().as_data().unwrap().0,
+                exp_block
+            );
+            client
+                .send_to(&Packet::new_ack(exp_block).serialize(), src)
+                .unwrap();
+        }
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_connection_table() {
+        let a1: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let a2: SocketAddr = "10.0.0.1:2000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+
+        let mut table = ConnectionTable::default();
+        assert_eq!(table.insert(a1, 1), None);
+        assert_eq!(table.insert(b, 2), None);
+        assert_eq!(table.count(), 2);
+        assert_eq!(table.count_for_peer(a1.ip()), 1);
+        assert_eq!(table.get(&a1), Some(&1));
+
+        // A lone transfer from an IP is the one a new port there belongs to
+        assert_eq!(table.find_rebind(a2), Some(a1));
+
+        // Replacing an entry doesn't count the peer twice
+        assert_eq!(table.insert(a1, 3), Some(1));
+        assert_eq!(table.count_for_peer(a1.ip()), 1);
+
+        assert_eq!(table.insert(a2, 4), None);
+        assert_eq!(table.count_for_peer(a1.ip()), 2);
+        assert_eq!(table.find_rebind("10.0.0.1:3000".parse().unwrap()), None);
+
+        assert_eq!(table.remove(&a1), Some(3));
+        assert_eq!(table.remove(&a1), None);
+        assert_eq!(table.get(&a1), None);
+        assert_eq!(table.count_for_peer(a1.ip()), 1);
+
+        table.retain(|&conn| conn != 4);
+        assert_eq!(table.count(), 1);
+        assert_eq!(table.count_for_peer(a2.ip()), 0);
+        assert_eq!(table.count_for_peer(b.ip()), 1);
+        assert!(!table.per_ip.contains_key(&a2.ip()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_bind_to_device() {
+        let root = temp_dir("bind-to-device");
+        fs::write(root.join("name.txt"), b"loopback").unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(bind_to_device(&socket, "no-such-if0").is_err());
+
+        let config = Config {
+            root: root.clone(),
+            interface: Some("lo".to_owned()),
+            ..Config::default()
+        };
+        let server = Arc::new(Server::from_socket(socket, config).unwrap());
+        let addr = server.local_addr().unwrap();
+
+        // Kernels before 5.7 want CAP_NET_RAW for this
+        if let Err(e) = bind_to_device(&*server.socket, "lo") {
+            assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+            fs::remove_dir_all(root).unwrap();
+            return;
+        }
+
+        let running = server.clone();
+        let handle = thread::spawn(move || running.run());
+        assert_eq!(download(&client(), addr, "name.txt"), b"loopback");
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_max_transfer_duration() {
+        let root = temp_dir("max-duration");
+        fs::write(root.join("slow.bin"), [b'x'; 512 * 20]).unwrap();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            max_transfer_duration: Some(Duration::from_millis(300)),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        // Each ACK comes well within the retransmit timeout, but the whole
+        // download would take two seconds
+        let client = client();
+        client
+            .send_to(&request(READ_OPCODE, "slow.bin", "octet", &[]), addr)
+            .unwrap();
+        let mut blocks = 0;
+        let err = loop {
+            match recv_from(&client) {
+                Packet::Data { block, .. } => {
+                    blocks += 1;
+                    thread::sleep(Duration::from_millis(100));
+                    client
+                        .send_to(&Packet::new_ack(block).serialize(), addr)
+                        .unwrap();
+                }
+                packet => break packet,
+            }
+        };
+        assert_eq!(err.error_code(), Some(SEE_MSG));
+        assert_eq!(err.error_msg(), Some("Transfer took too long"));
+        assert!(blocks < 20);
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_force_octet() {
+        let root = temp_dir("force-octet");
+        fs::write(root.join("raw.txt"), b"one\ntwo\r").unwrap();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            force_octet: true,
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let client = client();
+        client
+            .send_to(&request(READ_OPCODE, "raw.txt", "netascii", &[]), addr)
+            .unwrap();
+        assert_eq!(recv_from(&client).as_data(), Some((1, &b"one\ntwo\r"[..])));
+        client
+            .send_to(&Packet::new_ack(1).serialize(), addr)
+            .unwrap();
+
+        client
+            .send_to(&request(READ_OPCODE, "raw.txt", "mail", &[]), addr)
+            .unwrap();
+        assert_eq!(recv_from(&client).error_code(), Some(ILLEGAL_OP));
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_request_invalid_utf8() {
+        let (server, handle) = start_server(Config::default());
+        let addr = server.local_addr().unwrap();
+
+        let client = client();
+        client
+            .send_to(b"\x00\x01caf\xe9.txt\0octet\0", addr)
+            .unwrap();
+        assert_eq!(recv_from(&client).error_code(), Some(ILLEGAL_OP));
+
+        stop_server(&server, handle);
+    }
+
+    #[test]
+    fn test_max_request_size() {
+        let (server, handle) = start_server(Config {
+            max_request_size: 1024,
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        let long = "a".repeat(2048 - 2 - 1 - "octet".len() - 1);
+        let rrq = request(READ_OPCODE, &long, "octet", &[]);
+        assert_eq!(rrq.len(), 2048);
+        client.send_to(&rrq, addr).unwrap();
+        let reply = recv_from(&client);
+        assert_eq!(reply.error_code(), Some(ILLEGAL_OP));
+        assert_eq!(reply.error_msg(), Some("Request too long"));
+
+        // Within the cap it's looked at as usual, unknown options and all
+        let rrq = request(READ_OPCODE, "name.txt", "octet", &[("pad", &long[..900])]);
+        client.send_to(&rrq, addr).unwrap();
+        assert_eq!(recv_from(&client).error_code(), Some(FILE_NOT_FOUND));
+
+        stop_server(&server, handle);
+    }
+
+    /// The raw ERROR code bytes a server answers `rrq` with
+    fn error_code_bytes(addr: SocketAddr, rrq: &[u8]) -> [u8; 2] {
+        let client = client();
+        client.send_to(rrq, addr).unwrap();
+
+        let mut buf = [0; 516];
+        let (len, _) = client.recv_from(&mut buf).unwrap();
+        assert!(len >= 4);
+        assert_eq!(buf[..2], [0, 5]);
+
+        [buf[2], buf[3]]
+    }
+
+    #[test]
+    fn test_strict() {
+        let (server, handle) = start_server(Config {
+            strict: true,
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let rrq = request(READ_OPCODE, "missing.txt", "octet", &[]);
+        assert_eq!(error_code_bytes(addr, &rrq), FILE_NOT_FOUND.to_be_bytes());
+
+        // Refused for the option before the file is looked for
+        let rrq = request(READ_OPCODE, "missing.txt", "octet", &[("serverinfo", "")]);
+        assert_eq!(
+            error_code_bytes(addr, &rrq),
+            OPTION_NEGOTIATION.to_be_bytes()
+        );
+
+        let rrq = request(READ_OPCODE, "missing.txt", "octet", &[("blksize", "1024")]);
+        assert_eq!(error_code_bytes(addr, &rrq), FILE_NOT_FOUND.to_be_bytes());
+
+        stop_server(&server, handle);
+
+        let (server, handle) = start_server(Config::default());
+        let addr = server.local_addr().unwrap();
+        let rrq = request(READ_OPCODE, "missing.txt", "octet", &[("serverinfo", "")]);
+        assert_eq!(error_code_bytes(addr, &rrq), FILE_NOT_FOUND.to_le_bytes());
+        stop_server(&server, handle);
+    }
+
+    /// This chroots the whole test process, so it only runs on request:
+    /// `cargo test test_chroot -- --ignored`, as root.
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "needs root and chroots the test process"]
+    fn test_chroot() {
+        let root = temp_dir("chroot");
+        fs::write(root.join("name.txt"), b"inside").unwrap();
+
+        let (server, handle) = start_server(Config {
+            root,
+            chroot: true,
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        assert_eq!(download(&client(), addr, "name.txt"), b"inside");
+        assert_eq!(std::env::current_dir().unwrap(), Path::new("/"));
+        assert!(fs::read("/name.txt").is_ok());
+
+        stop_server(&server, handle);
+    }
+
+    #[test]
+    fn test_independent_servers() {
+        let roots = [temp_dir("independent-a"), temp_dir("independent-b")];
+        fs::write(roots[0].join("name.txt"), b"served by a").unwrap();
+        fs::write(roots[1].join("name.txt"), b"served by b").unwrap();
+
+        let servers: Vec<_> = roots
+            .iter()
+            .map(|root| {
+                start_server(Config {
+                    root: root.clone(),
+                    ..Config::default()
+                })
+            })
+            .collect();
+
+        let downloads: Vec<_> = servers
+            .iter()
+            .map(|(server, _)| {
+                let addr = server.local_addr().unwrap();
+                thread::spawn(move || download(&client(), addr, "name.txt"))
+            })
+            .collect();
+
+        let contents: Vec<_> = downloads.into_iter().map(|d| d.join().unwrap()).collect();
+        assert_eq!(contents, [b"served by a", b"served by b"]);
+
+        for (server, handle) in servers {
+            stop_server(&server, handle);
+        }
+        for root in roots {
+            fs::remove_dir_all(root).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_root_for() {
+        let config = Config {
+            subnet_roots: vec![
+                ("10.1.0.0/16".parse().unwrap(), PathBuf::from("/srv/vlan1")),
+                ("10.0.0.0/8".parse().unwrap(), PathBuf::from("/srv/lab")),
+            ],
+            ..Config::default()
+        };
+        let root = Path::new("/srv/tftp");
+        let root_for = |peer: &str| root_for(&config, root, peer.parse().unwrap(), &Mode::Octet);
+
+        assert_eq!(root_for("10.1.2.3"), Path::new("/srv/vlan1"));
+        assert_eq!(root_for("10.2.2.3"), Path::new("/srv/lab"));
+        assert_eq!(root_for("192.168.1.1"), root);
+        // From an IPv4 client of a dual-stack socket
+        assert_eq!(root_for("::ffff:10.1.2.3"), Path::new("/srv/vlan1"));
+    }
+
+    #[test]
+    fn test_check_peer() {
+        let config = Config {
+            peer_capabilities: vec![("10.0.0.0/8".parse().unwrap(), Capability::ReadOnly)],
+            ..Config::default()
+        };
+        let check = |peer: &str, op_code| {
+            check_peer(&config, peer.parse().unwrap(), op_code).map_err(|err| err.error_code())
+        };
+
+        assert_eq!(check("10.1.2.3", READ_OPCODE), Ok(()));
+        assert_eq!(check("10.1.2.3", WRITE_OPCODE), Err(Some(ACCESS_VIOLATION)));
+        assert_eq!(
+            check("::ffff:10.1.2.3", WRITE_OPCODE),
+            Err(Some(ACCESS_VIOLATION))
+        );
+        assert_eq!(check("192.168.1.1", WRITE_OPCODE), Ok(()));
+    }
+
+    #[test]
+    fn test_mode_subdirs() {
+        let root = temp_dir("mode_subdirs");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::create_dir_all(root.join("text")).unwrap();
+        fs::write(root.join("bin/name.txt"), b"binary").unwrap();
+        fs::write(root.join("text/name.txt"), b"text").unwrap();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            mode_subdirs: vec![
+                (Mode::Octet, PathBuf::from("bin")),
+                (Mode::NetAscii, PathBuf::from("text")),
+            ],
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        assert_eq!(download(&client(), addr, "name.txt"), b"binary");
+
+        let client = client();
+        let rrq = request(READ_OPCODE, "name.txt", "netascii", &[]);
+        client.send_to(&rrq, addr).unwrap();
+        match recv_from(&client) {
+            Packet::Data { block, data, .. } => {
+                assert_eq!(block, 1);
+                assert_eq!(data, b"text");
+            }
+            _ => panic!("did not get expected packet: Data"),
+        }
+        client
+            .send_to(&Packet::new_ack(1).serialize(), addr)
+            .unwrap();
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_subnet_roots() {
+        let root = temp_dir("subnet_roots");
+        let loopback = temp_dir("subnet_roots_loopback");
+        fs::write(root.join("name.txt"), b"default").unwrap();
+        fs::write(loopback.join("name.txt"), b"loopback").unwrap();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            subnet_roots: vec![("127.0.0.0/8".parse().unwrap(), loopback.clone())],
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        assert_eq!(download(&client(), addr, "name.txt"), b"loopback");
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+        fs::remove_dir_all(loopback).unwrap();
+    }
+
+    #[test]
+    fn test_resolve() {
+        let root = Path::new("/srv/tftp");
+
+        assert_eq!(
+            resolve(root, "/pxe/boot.img"),
+            Some(root.join("pxe/boot.img"))
+        );
+        assert_eq!(resolve(root, "pxe/../../etc/passwd"), None);
+        assert_eq!(resolve(root, "pxe/.boot.img.4242-7-0123abcd.part"), None);
+        assert_eq!(
+            resolve(root, "pxe/.boot.img.part"),
+            Some(root.join("pxe/.boot.img.part"))
+        );
+    }
+
+    fn spawn_read(
+        path: &Path,
+        options: Vec<(String, String)>,
+    ) -> (Sent, Sender<Packet>, JoinHandle<TransferResult>) {
+        let (transport, sent) = mock_transport();
+        let (tx, rx) = mpsc::channel();
+
+        let file = path.to_path_buf();
+        let worker = thread::spawn(move || {
+            read_process(
+                &Transfer {
+                    options,
+                    ..transfer(transport, rx, Direction::Read)
+                },
+                file,
+                Mode::Octet,
+                DEFAULT_IO_BUFFER,
+            )
+        });
+
+        (sent, tx, worker)
+    }
+
+    fn recv_packet(sent: &Sent) -> Packet {
+        let (bytes, _) = sent.recv_timeout(Duration::from_secs(5)).unwrap();
+        Packet::deserialize(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_read_process_startup() {
+        let path = temp_file("startup", b"hello world");
+
+        // Without options the first block goes out straight away
+        let (sent, tx, worker) = spawn_read(&path, Vec::new());
+        assert!(matches!(recv_packet(&sent), Packet::Data { block: 1, .. }));
+        tx.send(Packet::new_ack(1)).unwrap();
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+
+        // With an accepted option, block 1 waits for the OACK to be ACKed
+        let options = vec![("range".to_owned(), "0-11".to_owned())];
+        let (sent, tx, worker) = spawn_read(&path, options);
+        assert!(matches!(recv_packet(&sent), Packet::OAck { .. }));
+        assert!(sent.recv_timeout(Duration::from_millis(100)).is_err());
+
+        tx.send(Packet::new_ack(0)).unwrap();
+        assert!(matches!(recv_packet(&sent), Packet::Data { block: 1, .. }));
+        tx.send(Packet::new_ack(1)).unwrap();
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_timeout_option() {
+        let options = |options: &[(&str, &str)]| {
+            let options: Vec<_> = options
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            timeout(&options).map(|((name, _), timeout)| (name, timeout))
+        };
+
+        assert_eq!(
+            options(&[("timeout", "3")]),
+            Some(("timeout".to_owned(), Duration::from_secs(3)))
+        );
+        assert_eq!(
+            options(&[("timeout", "3"), ("utimeout", "500000")]),
+            Some(("utimeout".to_owned(), Duration::from_millis(500)))
+        );
+        assert_eq!(
+            options(&[("timeout", "3"), ("utimeout", "5")]),
+            Some(("timeout".to_owned(), Duration::from_secs(3)))
+        );
+        assert_eq!(options(&[("timeout", "0")]), None);
+    }
+
+    #[test]
+    fn test_read_process_utimeout() {
+        let path = temp_file("utimeout", b"hello world");
+
+        let options = vec![
+            ("timeout".to_owned(), "3".to_owned()),
+            ("utimeout".to_owned(), "500000".to_owned()),
+        ];
+        let (sent, tx, worker) = spawn_read(&path, options);
+
+        match recv_packet(&sent) {
+            Packet::OAck { options } => {
+                assert_eq!(options, [("utimeout".to_owned(), "500000".to_owned())]);
+            }
+            _ => panic!("did not get expected packet: OAck"),
+        }
+        tx.send(Packet::new_ack(0)).unwrap();
+
+        let (original, _) = sent.recv_timeout(Duration::from_secs(5)).unwrap();
+        // Well before the default timeout of a second would resend it
+        let (retransmitted, _) = sent.recv_timeout(Duration::from_millis(900)).unwrap();
+        assert_eq!(retransmitted, original);
+        tx.send(Packet::new_ack(1)).unwrap();
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "checksum")]
+    #[test]
+    fn test_read_process_checksum() {
+        let path = temp_file("checksum", b"abc");
+
+        let options = vec![("checksum".to_owned(), "SHA256".to_owned())];
+        let (sent, tx, worker) = spawn_read(&path, options);
+
+        // The FIPS 180-2 test vector for "abc"
+        let expected = "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        match recv_packet(&sent) {
+            Packet::OAck { options } => {
+                assert_eq!(options, [("checksum".to_owned(), expected.to_owned())]);
+            }
+            _ => panic!("did not get expected packet: OAck"),
+        }
+        tx.send(Packet::new_ack(0)).unwrap();
+
+        // Hashing didn't eat into what's sent
+        assert_eq!(recv_packet(&sent).as_data(), Some((1, &b"abc"[..])));
+        tx.send(Packet::new_ack(1)).unwrap();
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_process_range() {
+        let contents: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let path = temp_file("range", &contents);
+
+        let options = vec![("range".to_owned(), "100-200".to_owned())];
+        let (sent, tx, worker) = spawn_read(&path, options);
+
+        match recv_packet(&sent) {
+            Packet::OAck { options } => {
+                assert_eq!(options, [("range".to_owned(), "100-200".to_owned())]);
+            }
+            _ => panic!("did not get expected packet: OAck"),
+        }
+        tx.send(Packet::new_ack(0)).unwrap();
+
+        match recv_packet(&sent) {
+            Packet::Data { block, data, .. } => {
+                assert_eq!(block, 1);
+                assert_eq!(data, &contents[100..200]);
+            }
+            _ => panic!("did not get expected packet: Data"),
+        }
+        tx.send(Packet::new_ack(1)).unwrap();
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Acks blocks from a read worker until its final one, returning how
+    /// many bytes it sent. `change` runs once the first block has been sent.
+    fn read_changing_file(name: &str, size: usize, change: impl FnOnce(&Path)) -> usize {
+        let path = temp_file(name, &vec![b'a'; size]);
+        let (sent, tx, worker) = spawn_read(&path, Vec::new());
+
+        let mut change = Some(change);
+        let mut total = 0;
+        loop {
+            let (block, len) = match recv_packet(&sent) {
+                Packet::Data { block, len, .. } => (block, len),
+                _ => panic!("did not get expected packet: Data"),
+            };
+            total += len;
+
+            if let Some(change) = change.take() {
+                change(&path);
+            }
+            tx.send(Packet::new_ack(block)).unwrap();
+
+            if len < 512 {
+                break;
+            }
+        }
+
+        assert!(matches!(worker.join().unwrap(), TransferResult::Completed));
+        fs::remove_file(path).unwrap();
+
+        total
+    }
+
+    #[test]
+    fn test_read_process_file_grows() {
+        let sent = read_changing_file("grows", 600, |path| {
+            let mut file = fs::File::options().append(true).open(path).unwrap();
+            io::Write::write_all(&mut file, &[b'b'; 1000]).unwrap();
+        });
+        assert_eq!(sent, 600);
+    }
+
+    #[test]
+    fn test_read_process_file_truncated() {
+        // What's already buffered goes out as it was read, so the file is
+        // cut short past the first buffer's worth
+        let size = DEFAULT_IO_BUFFER + 1500;
+        let sent = read_changing_file("truncated", size, |path| {
+            let file = fs::File::options().write(true).open(path).unwrap();
+            file.set_len(DEFAULT_IO_BUFFER as u64 + 700).unwrap();
+        });
+        assert_eq!(sent, DEFAULT_IO_BUFFER + 700);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip_content_encoding() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let root = temp_dir("gzip");
+        // Long enough to take several blocks decompressed
+        let contents = b"kernel vmlinuz initrd=initrd.img\n".repeat(100);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        io::Write::write_all(&mut encoder, &contents).unwrap();
+        let compressed = encoder.finish().unwrap();
+        fs::write(root.join("boot.cfg.gz"), &compressed).unwrap();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            gzip: true,
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        // Clients that don't negotiate get the file decompressed
+        assert_eq!(download(&client(), addr, "boot.cfg"), contents);
+
+        let client = client();
+        let rrq = request(READ_OPCODE, "boot.cfg", "octet", &[("probe", "1")]);
+        client.send_to(&rrq, addr).unwrap();
+        match recv_from(&client) {
+            Packet::OAck { options } => assert_eq!(
+                options,
+                [
+                    ("exists".to_owned(), "1".to_owned()),
+                    ("tsize".to_owned(), contents.len().to_string())
+                ]
+            ),
+            _ => panic!("did not get expected packet: OAck"),
+        }
+
+        let rrq = request(
+            READ_OPCODE,
+            "boot.cfg",
+            "octet",
+            &[("content-encoding", "gzip")],
+        );
+        client.send_to(&rrq, addr).unwrap();
+        match recv_from(&client) {
+            Packet::OAck { options } => {
+                assert_eq!(
+                    options,
+                    [("content-encoding".to_owned(), "gzip".to_owned())]
+                );
+            }
+            _ => panic!("did not get expected packet: OAck"),
+        }
+        client
+            .send_to(&Packet::new_ack(0).serialize(), addr)
+            .unwrap();
+        match recv_from(&client) {
+            Packet::Data { block: 1, data, .. } => assert_eq!(data, compressed),
+            _ => panic!("did not get expected packet: Data"),
+        }
+        client
+            .send_to(&Packet::new_ack(1).serialize(), addr)
+            .unwrap();
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_serve_bytes() {
+        let root = temp_dir("serve-bytes");
+        fs::write(root.join("name.txt"), b"on disk").unwrap();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            ..Config::default()
+        });
+        server.serve_bytes("name.txt", b"in memory".to_vec());
+        server.serve_bytes("/pxe/boot.cfg", vec![b'x'; 600]);
+        let addr = server.local_addr().unwrap();
+
+        assert_eq!(download(&client(), addr, "name.txt"), b"in memory");
+        assert_eq!(download(&client(), addr, "pxe/boot.cfg"), [b'x'; 600]);
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_health() {
+        let (server, handle) = start_server(Config {
+            root: PathBuf::from("/nonexistent"),
+            enable_health: true,
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        assert_eq!(download(&client(), addr, HEALTH_FILE), b"OK");
+        assert!(server.is_running());
+
+        stop_server(&server, handle);
+        assert!(!server.is_running());
+    }
+
+    #[test]
+    fn test_request_log() {
+        let root = temp_dir("request_log");
+        fs::write(root.join("small.txt"), b"hello").unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            request_log: Some(log.clone()),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        assert_eq!(download(&client, addr, "small.txt"), b"hello");
+
+        let wrq = request(WRITE_OPCODE, "up.txt", "octet", &[("tsize", "3")]);
+        client.send_to(&wrq, addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::OAck { .. }));
+        let data = Packet::Data {
+            block: 1,
+            data: b"abc".to_vec(),
+            len: 3,
+        };
+        client.send_to(&data.serialize(), addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::Ack { block: 1 }));
+
+        // Refused before it's accepted, so never logged
+        let rrq = request(READ_OPCODE, "../escape.txt", "octet", &[]);
+        client.send_to(&rrq, addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::Error { .. }));
+
+        stop_server(&server, handle);
+
+        let log = String::from_utf8(log.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", log);
+        let peer = client.local_addr().unwrap();
+        for (line, rest) in lines.iter().zip([
+            format!(
+                "id=0 peer={} op=RRQ file=\"small.txt\" mode=octet options=\"\"",
+                peer
+            ),
+            format!(
+                "id=1 peer={} op=WRQ file=\"up.txt\" mode=octet options=\"tsize=3\"",
+                peer
+            ),
+        ]) {
+            let (time, fields) = line.split_once(' ').unwrap();
+            assert!(time.starts_with("time="), "{}", line);
+            assert_eq!(fields, rest);
+        }
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_transfer_ids() {
+        let root = temp_dir("transfer_ids");
+        fs::write(root.join("a.bin"), [b'a'; 1100]).unwrap();
+        fs::write(root.join("b.bin"), [b'b'; 1100]).unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (progress_tx, progress) = mpsc::channel();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            request_log: Some(log.clone()),
+            progress_tx: Some(progress_tx),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        // Run both at once, so their records interleave
+        let (a, b) = (client(), client());
+        for (client, file) in [(&a, "a.bin"), (&b, "b.bin")] {
+            client
+                .send_to(&request(READ_OPCODE, file, "octet", &[]), addr)
+                .unwrap();
+        }
+        let mut ids = HashMap::new();
+        for block in 1..=3 {
+            for (client, file) in [(&a, "a.bin"), (&b, "b.bin")] {
+                assert!(matches!(recv_from(client), Packet::Data { .. }));
+                if block == 1 {
+                    let info = server.active_transfers();
+                    let info = info.iter().find(|info| info.file == file).unwrap();
+                    ids.insert(file.to_owned(), info.id);
+                }
+                client
+                    .send_to(&Packet::new_ack(block).serialize(), addr)
+                    .unwrap();
+            }
+        }
+        stop_server(&server, handle);
+        assert_ne!(ids["a.bin"], ids["b.bin"]);
+
+        let log = String::from_utf8(log.lock().unwrap().clone()).unwrap();
+        assert_eq!(log.lines().count(), 2, "{}", log);
+        for line in log.lines() {
+            let file = if line.contains("a.bin") {
+                "a.bin"
+            } else {
+                "b.bin"
+            };
+            assert!(line.contains(&format!(" id={} ", ids[file])), "{}", line);
+        }
+
+        let events: Vec<_> = progress.try_iter().collect();
+        assert_eq!(events.len(), 6);
+        for event in events {
+            assert_eq!(event.id, ids[&event.file]);
+        }
+
+        let line = outcome_line(ids["a.bin"], addr, &TransferResult::TimedOut).unwrap();
+        assert!(line.starts_with(&format!("[transfer {}] ", ids["a.bin"])));
+        assert_eq!(outcome_line(0, addr, &TransferResult::Completed), None);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_worker_panic_follows_peer() {
+        let (transport, sent) = mock_transport();
+        let peer = Arc::new(Mutex::new(peer()));
+        let socket = Arc::new(PeerSocket {
+            inner: transport,
+            peer: peer.clone(),
+            delay: Duration::ZERO,
+        });
+
+        // The client moves before the worker gets to panic
+        let moved: SocketAddr = "127.0.0.1:7070".parse().unwrap();
+        let worker = spawn_worker(socket, 0, move || {
+            *peer.lock().unwrap() = moved;
+            panic!("worker fell over");
+        });
+        worker.join().unwrap();
+
+        let (bytes, dst) = sent.recv().unwrap();
+        assert_eq!(dst, moved);
+        assert!(matches!(
+            Packet::deserialize(&bytes).unwrap(),
+            Packet::Error { code: SEE_MSG, .. }
+        ));
+    }
+
+    #[test]
+    fn test_worker_panic() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .open_read_fn(|name| panic!("no way to open {}", name))
+            .build()
+            .unwrap();
+        let server = Arc::new(server);
+        let running = server.clone();
+        let handle = thread::spawn(move || running.run());
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        client
+            .send_to(&request(READ_OPCODE, "any.txt", "octet", &[]), addr)
+            .unwrap();
+        match recv_from(&client) {
+            Packet::Error { code, msg } => {
+                assert_eq!(code, SEE_MSG);
+                assert_eq!(msg, "Internal error");
+            }
+            _ => panic!("expected an ERROR"),
+        }
+
+        // The server carries on past it
+        assert!(server.is_running());
+        assert!(server.active_transfers().is_empty());
+
+        stop_server(&server, handle);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fifo() {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::FileTypeExt;
+
+        let root = temp_dir("fifo");
+        for name in ["in.pipe", "out.pipe"] {
+            let path = CString::new(root.join(name).as_os_str().as_bytes()).unwrap();
+            // SAFETY: `path` is NUL-terminated
+            assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        }
+        let contents: Vec<u8> = (0..1100).map(|i| b'a' + (i % 26) as u8).collect();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        // Fed by another process, as far as the server can tell
+        let feed = {
+            let (path, contents) = (root.join("in.pipe"), contents.clone());
+            thread::spawn(move || fs::write(path, contents).unwrap())
+        };
+        assert_eq!(download(&client, addr, "in.pipe"), contents);
+        feed.join().unwrap();
+
+        let drain = {
+            let path = root.join("out.pipe");
+            thread::spawn(move || fs::read(path).unwrap())
+        };
+        let wrq = request(WRITE_OPCODE, "out.pipe", "octet", &[]);
+        client.send_to(&wrq, addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::Ack { block: 0 }));
+        for (i, chunk) in contents.chunks(512).enumerate() {
+            let block = i as u16 + 1;
+            let data = Packet::new_data(block, chunk.to_vec(), chunk.len());
+            client.send_to(&data.serialize(), addr).unwrap();
+            assert!(matches!(recv_from(&client), Packet::Ack { block: b } if b == block));
+        }
+        assert_eq!(drain.join().unwrap(), contents);
+
+        // Written through, not replaced by a regular file
+        let metadata = fs::metadata(root.join("out.pipe")).unwrap();
+        assert!(metadata.file_type().is_fifo());
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Collects what's written to it where a test can still see it
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_open_hooks() {
+        let uploaded = SharedBuf::default();
+        let upload = uploaded.clone();
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .open_read_fn(|name| match name {
+                "generated.txt" => {
+                    let contents: Vec<u8> = (0..1000).map(|i| b'a' + (i % 26) as u8).collect();
+                    Ok(Box::new(io::Cursor::new(contents)))
+                }
+                _ => Err(io::ErrorKind::NotFound.into()),
+            })
+            .open_write_fn(move |_| Ok(Box::new(upload.clone())))
+            .build()
+            .unwrap();
+        let server = Arc::new(server);
+        let running = server.clone();
+        let handle = thread::spawn(move || running.run());
+        let addr = server.local_addr().unwrap();
+
+        let contents = download(&client(), addr, "generated.txt");
+        assert_eq!(contents.len(), 1000);
+        assert_eq!(&contents[..3], b"abc");
+
+        let client = client();
+        let rrq = request(READ_OPCODE, "missing.txt", "octet", &[]);
+        client.send_to(&rrq, addr).unwrap();
+        assert_eq!(recv_from(&client).error_code(), Some(FILE_NOT_FOUND));
+
+        let wrq = request(WRITE_OPCODE, "upload.txt", "octet", &[]);
+        client.send_to(&wrq, addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::Ack { block: 0 }));
+        let data = Packet::new_data(1, b"hooked".to_vec(), 6);
+        client.send_to(&data.serialize(), addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::Ack { block: 1 }));
+
+        stop_server(&server, handle);
+        assert_eq!(*uploaded.0.lock().unwrap(), b"hooked");
+    }
+
+    #[test]
+    fn test_index() {
+        let root = temp_dir("index");
+        fs::write(root.join("name.txt"), b"listed").unwrap();
+        fs::write(root.join("script.sh"), b"denied").unwrap();
+        fs::create_dir_all(root.join("subdir")).unwrap();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            denied_extensions: vec!["sh".to_owned()],
+            enable_index: true,
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let listing = download(&client(), addr, INDEX_FILE);
+        assert_eq!(listing, b"name.txt\n");
+
+        // Its worker answers a probe with the listing's length
+        let client = client();
+        let rrq = request(READ_OPCODE, INDEX_FILE, "octet", &[("probe", "1")]);
+        client.send_to(&rrq, addr).unwrap();
+        match recv_from(&client) {
+            Packet::OAck { options } => assert_eq!(
+                options,
+                [
+                    ("exists".to_owned(), "1".to_owned()),
+                    ("tsize".to_owned(), "9".to_owned())
+                ]
+            ),
+            _ => panic!("did not get expected packet: OAck"),
+        }
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_artificial_delay() {
+        let root = temp_dir("artificial_delay");
+        fs::write(root.join("name.txt"), [b'x'; 600]).unwrap();
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            artificial_delay: Duration::from_millis(300),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let client = client();
+        let rrq = request(READ_OPCODE, "name.txt", "octet", &[]);
+        client.send_to(&rrq, addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::Data { block: 1, .. }));
+
+        // DATA 2 is held back for longer than the client waits, so it gives
+        // up on it and ACKs block 1 again
+        client
+            .send_to(&Packet::new_ack(1).serialize(), addr)
+            .unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let mut buf = [0; 516];
+        assert!(client.recv_from(&mut buf).is_err());
+        client
+            .send_to(&Packet::new_ack(1).serialize(), addr)
+            .unwrap();
+
+        // The repeated ACK doesn't get the block sent twice
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(matches!(recv_from(&client), Packet::Data { block: 2, .. }));
+        client
+            .send_to(&Packet::new_ack(2).serialize(), addr)
+            .unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        assert!(client.recv_from(&mut buf).is_err());
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_index_long() {
+        let root = temp_dir("index_long");
+        fs::write(root.join("name.txt"), b"listed").unwrap();
+        let mtime = fs::metadata(root.join("name.txt"))
+            .unwrap()
+            .modified()
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            enable_index: true,
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let client = client();
+        let rrq = request(READ_OPCODE, INDEX_FILE, "octet", &[("listfmt", "long")]);
+        client.send_to(&rrq, addr).unwrap();
+
+        match recv_from(&client) {
+            Packet::OAck { options } => {
+                assert_eq!(options, [("listfmt".to_owned(), "long".to_owned())])
+            }
+            _ => panic!("did not get expected packet: OAck"),
+        }
+        client
+            .send_to(&Packet::new_ack(0).serialize(), addr)
+            .unwrap();
+
+        match recv_from(&client) {
+            Packet::Data { block, data, .. } => {
+                assert_eq!(block, 1);
+                assert_eq!(data, format!("6 {} name.txt\n", mtime).as_bytes());
+            }
+            _ => panic!("did not get expected packet: Data"),
+        }
+        client
+            .send_to(&Packet::new_ack(1).serialize(), addr)
+            .unwrap();
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_serverinfo_banner() {
+        let root = temp_dir("serverinfo");
+        fs::write(root.join("name.txt"), b"hello").unwrap();
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            banner: Some("tftp test".to_owned()),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let oack_options = |options: &[(&str, &str)]| {
+            let client = client();
+            let rrq = request(READ_OPCODE, "name.txt", "octet", options);
+            client.send_to(&rrq, addr).unwrap();
+
+            let options = match recv_from(&client) {
+                Packet::OAck { options } => options,
+                _ => panic!("did not get expected packet: OAck"),
+            };
+            client
+                .send_to(&Packet::new_error(0, "done").serialize(), addr)
+                .unwrap();
+
+            options
+        };
+
+        assert_eq!(
+            oack_options(&[("serverinfo", "")]),
+            [("serverinfo".to_owned(), "tftp test".to_owned())]
+        );
+        assert_eq!(
+            oack_options(&[("blksize", "1024")]),
+            [("blksize".to_owned(), "1024".to_owned())]
+        );
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_probe() {
+        let root = temp_dir("probe");
+        fs::write(root.join("name.txt"), b"hello").unwrap();
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let probe = |file: &str| {
+            let client = client();
+            let rrq = request(READ_OPCODE, file, "octet", &[("probe", "1")]);
+            client.send_to(&rrq, addr).unwrap();
+
+            match recv_from(&client) {
+                Packet::OAck { options } => options,
+                _ => panic!("did not get expected packet: OAck"),
+            }
+        };
+
+        assert_eq!(
+            probe("name.txt"),
+            [
+                ("exists".to_owned(), "1".to_owned()),
+                ("tsize".to_owned(), "5".to_owned())
+            ]
+        );
+        assert_eq!(
+            probe("missing.txt"),
+            [("exists".to_owned(), "0".to_owned())]
+        );
+        // Answered without starting a transfer
+        assert!(server.active_transfers().is_empty());
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_probe_not_found_dropped() {
+        let root = temp_dir("probe_dropped");
+        fs::write(root.join("name.txt"), b"hello").unwrap();
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            not_found_behavior: NotFoundBehavior::Drop,
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        let rrq = request(READ_OPCODE, "name.txt", "octet", &[("probe", "1")]);
+        client.send_to(&rrq, addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::OAck { .. }));
+
+        // Silent, as for a plain RRQ, so files can't be told apart by probing
+        let rrq = request(READ_OPCODE, "missing.txt", "octet", &[("probe", "1")]);
+        client.send_to(&rrq, addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(client.recv_from(&mut [0; 516]).is_err());
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_log_sink() {
+        let mut sink = LogSink::new(Vec::new(), peer());
+        sink.write_all(b"panic at 0x1f\r\nbacktr").unwrap();
+        sink.write_all(b"ace:\n").unwrap();
+        sink.write_all(b"no newline").unwrap();
+        sink.flush().unwrap();
+        assert_eq!(
+            String::from_utf8(sink.out).unwrap(),
+            format!(
+                "{0}: panic at 0x1f\n{0}: backtrace:\n{0}: no newline\n",
+                peer()
+            )
+        );
+
+        // A line that runs on is split rather than held on to
+        let mut sink = LogSink::new(Vec::new(), peer());
+        sink.write_all(&[b'x'; LOG_LINE_MAX + 10]).unwrap();
+        assert_eq!(sink.line.len(), 10);
+        sink.write_all(b"\n").unwrap();
+        let logged = String::from_utf8(sink.out).unwrap();
+        let lines: Vec<_> = logged.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                format!("{}: {}", peer(), "x".repeat(LOG_LINE_MAX)),
+                format!("{}: {}", peer(), "x".repeat(10))
+            ]
+        );
+
+        let root = temp_dir("log-sink");
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            enable_log_sink: true,
+            log_sink_writer: Some(log.clone()),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        let wrq = request(WRITE_OPCODE, LOG_SINK_FILE, "octet", &[]);
+        client.send_to(&wrq, addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::Ack { block: 0 }));
+        let data = Packet::new_data(1, b"crash dump\n".to_vec(), 11);
+        client.send_to(&data.serialize(), addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::Ack { block: 1 }));
+
+        stop_server(&server, handle);
+        // Logged rather than stored
+        assert_eq!(
+            String::from_utf8(log.lock().unwrap().clone()).unwrap(),
+            format!("{}: crash dump\n", client.local_addr().unwrap())
+        );
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    fn write_nested(create_dirs: bool) {
+        let root = temp_dir(&format!("create-dirs-{}", create_dirs));
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            create_dirs,
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        let wrq = request(WRITE_OPCODE, "a/b/name.txt", "octet", &[]);
+        client.send_to(&wrq, addr).unwrap();
+
+        if create_dirs {
+            assert!(matches!(recv_from(&client), Packet::Ack { block: 0 }));
+            client
+                .send_to(
+                    &Packet::new_data(1, b"nested".to_vec(), 6).serialize(),
+                    addr,
+                )
+                .unwrap();
+            assert!(matches!(recv_from(&client), Packet::Ack { block: 1 }));
+        } else {
+            assert_eq!(recv_from(&client).error_code(), Some(FILE_NOT_FOUND));
+        }
+
+        stop_server(&server, handle);
+        if create_dirs {
+            assert_eq!(fs::read(root.join("a/b/name.txt")).unwrap(), b"nested");
+        } else {
+            assert!(!root.join("a").exists());
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_preserve_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = temp_dir("preserve-metadata");
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            preserve_metadata: true,
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        let options = [("mtime", "1000000000"), ("mode", "755")];
+        let wrq = request(WRITE_OPCODE, "tool.sh", "octet", &options);
+        client.send_to(&wrq, addr).unwrap();
+        match recv_from(&client) {
+            Packet::OAck { options } => assert_eq!(
+                options,
+                [
+                    ("mtime".to_owned(), "1000000000".to_owned()),
+                    ("mode".to_owned(), "755".to_owned())
+                ]
+            ),
+            _ => panic!("did not get expected packet: OAck"),
+        }
+        let data = Packet::new_data(1, b"#!/bin/sh\n".to_vec(), 10);
+        client.send_to(&data.serialize(), addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::Ack { block: 1 }));
+
+        let wrq = request(WRITE_OPCODE, "other.sh", "octet", &[("mtime", "yesterday")]);
+        client.send_to(&wrq, addr).unwrap();
+        assert_eq!(recv_from(&client).error_code(), Some(OPTION_NEGOTIATION));
+
+        stop_server(&server, handle);
+        let metadata = fs::metadata(root.join("tool.sh")).unwrap();
+        assert_eq!(
+            metadata.modified().unwrap(),
+            UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+        );
+        assert_eq!(metadata.permissions().mode() & 0o111, 0o111);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_wrq() {
+        let root = temp_dir("duplicate-wrq");
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        // The first ACK is taken as lost, so the WRQ goes out again
+        let wrq = request(WRITE_OPCODE, "name.txt", "octet", &[]);
+        client.send_to(&wrq, addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::Ack { block: 0 }));
+        client.send_to(&wrq, addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::Ack { block: 0 }));
+
+        let data = Packet::new_data(1, b"once".to_vec(), 4);
+        client.send_to(&data.serialize(), addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::Ack { block: 1 }));
+
+        // Too late to be the request being retried; it's ignored
+        client.send_to(&wrq, addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(client.recv_from(&mut [0; 516]).is_err());
+
+        stop_server(&server, handle);
+        assert_eq!(fs::read(root.join("name.txt")).unwrap(), b"once");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_rrq_with_options() {
+        let root = temp_dir("duplicate-rrq");
+        fs::write(root.join("name.txt"), b"hello").unwrap();
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        // The OACK is taken as lost, so the RRQ goes out again. It's answered
+        // at once, well before the OACK would be resent for lack of an ACK.
+        let rrq = request(READ_OPCODE, "name.txt", "octet", &[("blksize", "1024")]);
+        client.set_read_timeout(Some(ACK_TIMEOUT / 2)).unwrap();
+        for _ in 0..2 {
+            client.send_to(&rrq, addr).unwrap();
+            match recv_from(&client) {
+                Packet::OAck { options } => {
+                    assert_eq!(options, [("blksize".to_owned(), "1024".to_owned())])
+                }
+                _ => panic!("did not get expected packet: OAck"),
+            }
+        }
+
+        client
+            .send_to(&Packet::new_ack(0).serialize(), addr)
+            .unwrap();
+        match recv_from(&client) {
+            Packet::Data { block: 1, data, .. } => assert_eq!(data, b"hello"),
+            _ => panic!("did not get expected packet: Data"),
+        }
+        client
+            .send_to(&Packet::new_ack(1).serialize(), addr)
+            .unwrap();
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_wrq_create_dirs() {
+        write_nested(true);
+    }
+
+    #[test]
+    fn test_wrq_missing_dir() {
+        write_nested(false);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_dirs_through_symlink() {
+        let root = temp_dir("create-dirs-symlink");
+        let outside = temp_dir("create-dirs-outside");
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+
+        let res = create_dirs(&root, &root.join("link/escaped"));
+        assert_eq!(res.err().unwrap().error_code(), Some(ACCESS_VIOLATION));
+        assert!(!outside.join("escaped").exists());
+
+        fs::remove_dir_all(root).unwrap();
+        fs::remove_dir_all(outside).unwrap();
+    }
+
+    /// Starts a write of `name.txt` and, while it's in progress, a read of
+    /// the same file. Returns what the reader was sent, if it was served.
+    fn read_during_write(busy_behavior: BusyBehavior) -> Vec<u8> {
+        let root = temp_dir(&format!("busy-{:?}", busy_behavior));
+        fs::write(root.join("name.txt"), b"old contents").unwrap();
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            busy_behavior,
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let writer = client();
+        let wrq = request(WRITE_OPCODE, "name.txt", "octet", &[]);
+        writer.send_to(&wrq, addr).unwrap();
+        assert!(matches!(recv_from(&writer), Packet::Ack { block: 0 }));
+
+        let reader = client();
+        let rrq = request(READ_OPCODE, "name.txt", "octet", &[]);
+        reader.send_to(&rrq, addr).unwrap();
+
+        let reply = match busy_behavior {
+            BusyBehavior::Reject => {
+                assert_eq!(recv_from(&reader).error_code(), Some(SEE_MSG));
+                Vec::new()
+            }
+            BusyBehavior::Wait => {
+                // Nothing until the write is over
+                reader
+                    .set_read_timeout(Some(Duration::from_millis(200)))
+                    .unwrap();
+                assert!(reader.recv_from(&mut [0; 516]).is_err());
+                reader
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+
+                let data = Packet::new_data(1, b"new".to_vec(), 3);
+                writer.send_to(&data.serialize(), addr).unwrap();
+                assert!(matches!(recv_from(&writer), Packet::Ack { block: 1 }));
+
+                match recv_from(&reader) {
+                    Packet::Data { block: 1, data, .. } => {
+                        reader
+                            .send_to(&Packet::new_ack(1).serialize(), addr)
+                            .unwrap();
+                        data
+                    }
+                    _ => panic!("did not get expected packet: Data"),
+                }
+            }
+        };
+
+        if busy_behavior == BusyBehavior::Reject {
+            let data = Packet::new_data(1, b"new".to_vec(), 3);
+            writer.send_to(&data.serialize(), addr).unwrap();
+            assert!(matches!(recv_from(&writer), Packet::Ack { block: 1 }));
+        }
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+
+        reply
+    }
+
+    #[test]
+    fn test_read_during_write_rejected() {
+        read_during_write(BusyBehavior::Reject);
+    }
+
+    #[test]
+    fn test_read_during_write_waits() {
+        assert_eq!(read_during_write(BusyBehavior::Wait), b"new");
+    }
+
+    #[test]
+    fn test_wrq_tsize_preflight() {
+        let root = temp_dir("preflight");
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            free_space: |_| Some(1000),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        let wrq = request(WRITE_OPCODE, "big.img", "octet", &[("tsize", "5000")]);
+        client.send_to(&wrq, addr).unwrap();
+        assert_eq!(recv_from(&client).error_code(), Some(DISK_FULL));
+        assert!(!root.join("big.img").exists());
+
+        let wrq = request(WRITE_OPCODE, "small.img", "octet", &[("tsize", "10")]);
+        client.send_to(&wrq, addr).unwrap();
+        match recv_from(&client) {
+            Packet::OAck { options } => {
+                assert_eq!(options, [("tsize".to_owned(), "10".to_owned())]);
+            }
+            _ => panic!("did not get expected packet: OAck"),
+        }
+        client
+            .send_to(&Packet::new_error(0, "done").serialize(), addr)
+            .unwrap();
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    fn request_missing(not_found_behavior: NotFoundBehavior) -> io::Result<Packet> {
+        let root = temp_dir(&format!("not-found-{:?}", not_found_behavior));
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            not_found_behavior,
+            ..Config::default()
+        });
+        let client = client();
+        client
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+
+        let rrq = request(READ_OPCODE, "missing.txt", "octet", &[]);
+        client.send_to(&rrq, server.local_addr().unwrap()).unwrap();
+
+        let mut buf = [0; 516];
+        let reply = client
+            .recv_from(&mut buf)
+            .map(|(len, _)| Packet::deserialize(&buf[..len]).unwrap());
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+
+        reply
+    }
+
+    #[test]
+    fn test_not_found_error() {
+        let reply = request_missing(NotFoundBehavior::Error).unwrap();
+        assert_eq!(reply.error_code(), Some(FILE_NOT_FOUND));
+    }
+
+    #[test]
+    fn test_not_found_drop() {
+        let err = request_missing(NotFoundBehavior::Drop).err().unwrap();
+        assert!(matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+    }
+
+    #[test]
+    fn test_wrq_large_block() {
+        let root = temp_dir("large-block");
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        let wrq = request(WRITE_OPCODE, "big.bin", "octet", &[("blksize", "8192")]);
+        client.send_to(&wrq, addr).unwrap();
+        assert!(matches!(recv_from(&client), Packet::OAck { .. }));
+
+        let contents: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        for (block, data) in [(1, contents.clone()), (2, Vec::new())] {
+            let len = data.len();
+            let packet = Packet::new_data(block, data, len);
+            client.send_to(&packet.serialize(), addr).unwrap();
+            assert!(matches!(recv_from(&client), Packet::Ack { block: b } if b == block));
+        }
+
+        stop_server(&server, handle);
+        assert_eq!(fs::read(root.join("big.bin")).unwrap(), contents);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_data_without_transfer() {
+        let (server, handle) = start_server(Config::default());
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        let data = Packet::new_data(1, b"stray".to_vec(), 5);
+        client.send_to(&data.serialize(), addr).unwrap();
+        let reply = recv_from(&client);
+        assert_eq!(reply.error_code(), Some(UNKNOWN_TID));
+        assert_eq!(reply.error_msg(), Some("No transfer in progress"));
+
+        // An ERROR isn't answered at all
+        client
+            .send_to(&Packet::new_error(0, "stray").serialize(), addr)
+            .unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(client.recv_from(&mut [0; 516]).is_err());
+
+        stop_server(&server, handle);
+    }
+
+    #[test]
+    fn test_packet_after_transfer_finished() {
+        let (server, handle) = start_server(Config::default());
+        server.serve_bytes("name.txt", b"hello".to_vec());
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        assert_eq!(download(&client, addr, "name.txt"), b"hello");
+        // The worker is gone by now, though likely still in the table until
+        // the serve loop next wakes up
+        thread::sleep(Duration::from_millis(20));
+        client
+            .send_to(&Packet::new_ack(1).serialize(), addr)
+            .unwrap();
+        assert_eq!(recv_from(&client).error_code(), Some(UNKNOWN_TID));
+
+        // And the server carries on
+        assert_eq!(download(&client, addr, "name.txt"), b"hello");
+
+        stop_server(&server, handle);
+    }
+
+    #[test]
+    fn test_ack_without_transfer() {
+        let (server, handle) = start_server(Config::default());
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        client
+            .send_to(&Packet::new_ack(1).serialize(), addr)
+            .unwrap();
+        let reply = recv_from(&client);
+        assert_eq!(reply.error_code(), Some(UNKNOWN_TID));
+        assert_eq!(reply.error_msg(), Some("No transfer in progress"));
+
+        stop_server(&server, handle);
+    }
+
+    #[test]
+    fn test_truncated_datagram() {
+        let (server, handle) = start_server(Config::default());
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        for bytes in [&[0][..], &[0, 4, 0]] {
+            client.send_to(bytes, addr).unwrap();
+            assert_eq!(recv_from(&client).error_code(), Some(ILLEGAL_OP));
+        }
+        assert!(server.is_running());
+
+        stop_server(&server, handle);
+    }
+
+    #[test]
+    fn test_oack_to_server() {
+        let (server, handle) = start_server(Config::default());
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        let oack = Packet::OAck {
+            options: vec![("blksize".to_owned(), "1432".to_owned())],
+        };
+        client.send_to(&oack.serialize(), addr).unwrap();
+        assert_eq!(recv_from(&client).error_code(), Some(ILLEGAL_OP));
+
+        stop_server(&server, handle);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.img", "boot.img"));
+        assert!(glob_match("*.img", "pxe/boot.img"));
+        assert!(glob_match("boot-?.img", "boot-1.img"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("*.img", "boot.img.txt"));
+        assert!(!glob_match("boot-?.img", "boot-10.img"));
+    }
+
+    /// The block size `negotiate_blksize` settles on for a request, if any
+    fn negotiated_blksize(config: &Config, file: &str, options: &[(&str, &str)]) -> Option<String> {
+        let mut options: Vec<_> = options
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        negotiate_blksize(config, file, &mut options);
+
+        options
+            .into_iter()
+            .find(|(name, _)| name == "blksize")
+            .map(|(_, value)| value)
+    }
+
+    #[test]
+    fn test_negotiate_blksize_precedence() {
+        let config = Config {
+            blksize: 1024,
+            blksize_overrides: vec![("*.img".to_owned(), 1432)],
+            ..Config::default()
+        };
+        let negotiate = |file, options| negotiated_blksize(&config, file, options);
+
+        // Client request > per-file config > global default
+        assert_eq!(
+            negotiate("boot.img", &[("blksize", "8192")]).unwrap(),
+            "8192"
+        );
+        assert_eq!(negotiate("boot.img", &[("tsize", "0")]).unwrap(), "1432");
+        assert_eq!(negotiate("boot.cfg", &[("tsize", "0")]).unwrap(), "1024");
+
+        // A size past the maximum is cut down to it, and one below the
+        // minimum isn't a size at all
+        assert_eq!(
+            negotiate("boot.img", &[("blksize", "100000")]).unwrap(),
+            MAX_BLKSIZE.to_string()
+        );
+        assert_eq!(negotiate("boot.img", &[("blksize", "4")]), None);
+        assert_eq!(negotiate("boot.img", &[("blksize", "0")]), None);
+        assert_eq!(negotiate("boot.img", &[("blksize", "8")]).unwrap(), "8");
+
+        // Clients that don't negotiate are never sent an OACK
+        assert_eq!(negotiate("boot.img", &[]), None);
+    }
+
+    #[test]
+    fn test_negotiate_blksize_strict() {
+        let config = Config {
+            strict: true,
+            blksize: 1024,
+            blksize_overrides: vec![("*.img".to_owned(), 1432)],
+            ..Config::default()
+        };
+        let negotiate = |file, options| negotiated_blksize(&config, file, options);
+
+        // Only a block size the client asked about is answered
+        assert_eq!(negotiate("boot.img", &[("tsize", "0")]), None);
+        assert_eq!(negotiate("boot.cfg", &[("tsize", "0")]), None);
+        assert_eq!(
+            negotiate("boot.img", &[("blksize", "8192")]).unwrap(),
+            "8192"
+        );
+    }
+
+    #[test]
+    fn test_per_file_blksize() {
+        let root = temp_dir("per-file-blksize");
+        fs::write(root.join("boot.img"), [b'i'; 2000]).unwrap();
+        fs::write(root.join("boot.cfg"), [b'c'; 2000]).unwrap();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            blksize_overrides: vec![("*.img".to_owned(), 1432)],
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+        let client = client();
+
+        // A client that negotiates, asking for the size but not the block size
+        let first_block = |file: &str| {
+            let rrq = request(READ_OPCODE, file, "octet", &[("tsize", "0")]);
+            client.send_to(&rrq, addr).unwrap();
+
+            let oack = match recv_from(&client) {
+                Packet::OAck { options } => options,
+                _ => panic!("did not get expected packet: OAck"),
+            };
+            client
+                .send_to(&Packet::new_ack(0).serialize(), addr)
+                .unwrap();
+
+            let len = match recv_from(&client) {
+                Packet::Data { len, .. } => len,
+                _ => panic!("did not get expected packet: Data"),
+            };
+            client
+                .send_to(&Packet::new_error(0, "done").serialize(), addr)
+                .unwrap();
+
+            (oack, len)
+        };
+
+        let (oack, len) = first_block("boot.img");
+        assert!(oack.contains(&("blksize".to_owned(), "1432".to_owned())));
+        assert_eq!(len, 1432);
+
+        let (oack, len) = first_block("boot.cfg");
+        assert!(oack.iter().all(|(name, _)| name != "blksize"));
+        assert_eq!(len, 512);
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Starts a download from one port and ACKs its first block from another
+    fn ack_from_new_port(tolerate_nat_rebind: bool) -> Packet {
+        let root = temp_dir(&format!("rebind-{}", tolerate_nat_rebind));
+        fs::write(root.join("file.bin"), [b'x'; 1000]).unwrap();
+
+        let (server, handle) = start_server(Config {
+            root: root.clone(),
+            tolerate_nat_rebind,
+            ..Config::default()
+        });
+        let addr = server.local_addr().unwrap();
+
+        let before = client();
+        let rrq = request(READ_OPCODE, "file.bin", "octet", &[]);
+        before.send_to(&rrq, addr).unwrap();
+        assert!(matches!(recv_from(&before), Packet::Data { block: 1, .. }));
+
+        let after = client();
+        after
+            .send_to(&Packet::new_ack(1).serialize(), addr)
+            .unwrap();
+        let res = recv_from(&after);
+
+        // Whatever happened, make sure the transfer finishes
+        let err = Packet::new_error(0, "done").serialize();
+        before.send_to(&err, addr).unwrap();
+        after.send_to(&err, addr).unwrap();
+
+        stop_server(&server, handle);
+        fs::remove_dir_all(root).unwrap();
+
+        res
+    }
+
+    #[test]
+    fn test_nat_rebind_tolerated() {
+        match ack_from_new_port(true) {
+            Packet::Data { block, len, .. } => {
+                assert_eq!(block, 2);
+                assert_eq!(len, 488);
+            }
+            _ => panic!("did not get expected packet: Data"),
+        }
+    }
+
+    #[test]
+    fn test_nat_rebind_not_tolerated() {
+        assert_eq!(ack_from_new_port(false).error_code(), Some(UNKNOWN_TID));
+    }
+}